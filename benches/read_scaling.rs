@@ -0,0 +1,50 @@
+// Measures `get` throughput on a warm cache as the number of reading threads
+// grows. Run with `cargo bench --bench read_scaling`.
+
+extern crate sync_lru;
+
+use std::sync::{ Arc, Barrier };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use sync_lru::LruCache;
+
+const ENTRIES: u64 = 10_000;
+const RUN_FOR: Duration = Duration::from_millis(500);
+
+fn run(cache: &Arc<LruCache<u64, u64>>, threads: u64) -> f64 {
+  let barrier = Arc::new(Barrier::new(threads as usize));
+  let handles: Vec<_> = (0..threads).map(|t| {
+    let cache = cache.clone();
+    let barrier = barrier.clone();
+    thread::spawn(move || {
+      barrier.wait();
+      let start = Instant::now();
+      let mut ops = 0u64;
+      // A cheap LCG so each thread reads a different spread of keys.
+      let mut x = t.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+      while start.elapsed() < RUN_FOR {
+        for _ in 0..1000 {
+          x = x.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+          assert!(cache.get(&((x >> 33) % ENTRIES)).is_some());
+        }
+        ops += 1000;
+      }
+      ops as f64 / start.elapsed().as_secs_f64()
+    })
+  }).collect();
+  handles.into_iter().map(|handle| handle.join().unwrap()).sum()
+}
+
+fn main() {
+  let cache = Arc::new(LruCache::with_limit(ENTRIES as usize));
+  for i in 0..ENTRIES {
+    cache.insert(i, i);
+  }
+  let single = run(&cache, 1);
+  println!("{:>2} threads: {:>12.0} gets/s", 1, single);
+  for &threads in &[2, 4, 8] {
+    let ops = run(&cache, threads);
+    println!("{:>2} threads: {:>12.0} gets/s ({:.2}x)", threads, ops, ops / single);
+  }
+}
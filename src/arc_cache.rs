@@ -0,0 +1,49 @@
+use std::hash::Hash;
+use std::ops::Deref;
+
+use LruCache;
+use policy::Adaptive;
+
+// An `LruCache` that evicts with Adaptive Replacement instead of plain LRU,
+// and has the same API through `Deref`. Use `LruCache::builder` with an
+// `Adaptive` policy to combine it with other settings.
+pub struct ArcCache<K, V: Send> {
+  cache: LruCache<K, V>,
+}
+
+impl<K: Clone + Hash + Eq, V: Send> ArcCache<K, V> {
+  pub fn with_limit(limit: usize) -> ArcCache<K, V> {
+    ArcCache {
+      cache: LruCache::builder(limit).eviction_policy(Adaptive::new()).build(),
+    }
+  }
+}
+
+impl<K, V: Send> Deref for ArcCache<K, V> {
+  type Target = LruCache<K, V>;
+
+  fn deref(&self) -> &LruCache<K, V> {
+    &self.cache
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn frequent_entries_survive_scan() {
+    let cash = ArcCache::with_limit(10);
+    for i in 0..5u32 {
+      cash.insert(i, i);
+      cash.get(&i);
+    }
+    for i in 100..200u32 {
+      cash.insert(i, i);
+    }
+    for i in 0..5u32 {
+      assert_eq!(cash.get(&i).map(|a| *a), Some(i));
+    }
+    assert_eq!(cash.len(), 10);
+  }
+}
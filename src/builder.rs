@@ -0,0 +1,126 @@
+use std::sync::Arc;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use { nanos, Clock, Listener, LruCache, RemovalCause, SystemClock, Weigher };
+use policy::{ EvictionPolicy, Lru };
+
+pub struct Builder<K, V> {
+  limit: usize,
+  max_weight: u64,
+  weigher: Option<Box<Weigher<K, V>>>,
+  expire_after_write: Option<Duration>,
+  expire_after_access: Option<Duration>,
+  clock: Arc<dyn Clock>,
+  listener: Option<Box<Listener<K, V>>>,
+  stats: bool,
+  policy: Box<dyn EvictionPolicy>,
+  negative: Option<(usize, Duration)>,
+  marker: PhantomData<fn(K, V)>,
+}
+
+impl<K: Clone + Hash + Eq, V: Send> Builder<K, V> {
+  pub fn new(limit: usize) -> Builder<K, V> {
+    assert!(limit != 0);
+    Builder {
+      limit,
+      max_weight: u64::MAX,
+      weigher: None,
+      expire_after_write: None,
+      expire_after_access: None,
+      clock: Arc::new(SystemClock),
+      listener: None,
+      stats: false,
+      policy: Box::new(Lru::new()),
+      negative: None,
+      marker: PhantomData,
+    }
+  }
+
+  // Entries are evicted until their total weight is at most `max_weight`, as
+  // well as their count being at most the limit.
+  pub fn max_weight(mut self, max_weight: u64) -> Builder<K, V> {
+    self.max_weight = max_weight;
+    self
+  }
+
+  // Without a weigher every entry weighs 1.
+  pub fn weigher<F>(mut self, weigher: F) -> Builder<K, V>
+      where F: Fn(&K, &V) -> u64 + Send + Sync + 'static {
+    self.weigher = Some(Box::new(weigher));
+    self
+  }
+
+  // Entries expire this long after they were inserted, unless inserted with
+  // `insert_with_ttl`.
+  pub fn expire_after_write(mut self, ttl: Duration) -> Builder<K, V> {
+    self.expire_after_write = Some(ttl);
+    self
+  }
+
+  // Entries expire once they have gone this long without a `get`.
+  pub fn expire_after_access(mut self, tti: Duration) -> Builder<K, V> {
+    self.expire_after_access = Some(tti);
+    self
+  }
+
+  // Defaults to `SystemClock`.
+  pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Builder<K, V> {
+    self.clock = Arc::new(clock);
+    self
+  }
+
+  // Called with every entry that leaves the cache, after the cache's lock has
+  // been released.
+  pub fn removal_listener<F>(mut self, listener: F) -> Builder<K, V>
+      where F: Fn(K, Arc<V>, RemovalCause) + Send + Sync + 'static {
+    self.listener = Some(Box::new(listener));
+    self
+  }
+
+  // Enables the counters returned by `LruCache::stats`.
+  pub fn record_stats(mut self) -> Builder<K, V> {
+    self.stats = true;
+    self
+  }
+
+  // Defaults to `Lru`.
+  pub fn eviction_policy<P: EvictionPolicy + 'static>(mut self, policy: P) -> Builder<K, V> {
+    self.policy = Box::new(policy);
+    self
+  }
+
+  // Remembers up to `limit` keys that `get_or_insert_with_optional` found no
+  // value for, each for `ttl`. They do not count towards the cache's own
+  // limit or weight.
+  pub fn negative_cache(mut self, limit: usize, ttl: Duration) -> Builder<K, V> {
+    assert!(limit != 0);
+    self.negative = Some((limit, ttl));
+    self
+  }
+
+  pub fn build(self) -> LruCache<K, V> {
+    let negative = self.negative.map(|(limit, ttl)| {
+      let mut negative = Builder::new(limit).expire_after_write(ttl);
+      negative.clock = self.clock.clone();
+      Box::new(negative.build())
+    });
+    let mut cache = LruCache::new(
+      self.limit,
+      self.clock,
+      self.listener,
+      self.weigher,
+      self.stats,
+      self.policy);
+    {
+      let inner = cache.inner.get_mut().unwrap();
+      inner.max_weight = self.max_weight;
+      inner.expire_after_write = self.expire_after_write.map(nanos);
+      inner.expire_after_access = self.expire_after_access.map(nanos);
+      inner.policy.set_capacity(self.limit);
+    }
+    cache.negative = negative;
+    cache
+  }
+}
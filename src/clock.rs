@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+
+use nanos;
+
+// The time source used for expiration. Must never go backwards.
+pub trait Clock: Send + Sync {
+  fn now(&self) -> Instant;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+}
+
+// A clock that only moves when told to. Clones share the same time, so a test
+// can keep one and hand another to the cache.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+  start: Instant,
+  offset: Arc<AtomicU64>,
+}
+
+impl MockClock {
+  pub fn new() -> MockClock {
+    MockClock {
+      start: Instant::now(),
+      offset: Arc::new(AtomicU64::new(0)),
+    }
+  }
+
+  pub fn advance(&self, by: Duration) {
+    self.offset.fetch_add(nanos(by), Ordering::SeqCst);
+  }
+}
+
+impl Default for MockClock {
+  fn default() -> MockClock {
+    MockClock::new()
+  }
+}
+
+impl Clock for MockClock {
+  fn now(&self) -> Instant {
+    self.start + Duration::from_nanos(self.offset.load(Ordering::SeqCst))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn mock_clock_advances() {
+    let clock = MockClock::new();
+    let handle = clock.clone();
+    let before = clock.now();
+    assert_eq!(clock.now(), before);
+    handle.advance(Duration::from_secs(5));
+    assert_eq!(clock.now() - before, Duration::from_secs(5));
+  }
+}
@@ -0,0 +1,190 @@
+use std::sync::{ Arc, RwLockWriteGuard };
+use std::hash::Hash;
+
+use { Inner, LruCache, RemovalCause };
+
+// A single key of an `LruCache`, from `LruCache::entry`. The cache stays
+// locked until the entry is dropped, so the closures passed to it must not
+// use the cache themselves.
+pub enum Entry<'a, K: Clone + Hash + Eq + 'a, V: Send + 'a> {
+  Occupied(OccupiedEntry<'a, K, V>),
+  Vacant(VacantEntry<'a, K, V>),
+}
+
+pub struct OccupiedEntry<'a, K: Clone + Hash + Eq + 'a, V: Send + 'a> {
+  lock: Locked<'a, K, V>,
+  slot: usize,
+}
+
+pub struct VacantEntry<'a, K: Clone + Hash + Eq + 'a, V: Send + 'a> {
+  lock: Locked<'a, K, V>,
+  key: K,
+}
+
+// The write lock, released through `LruCache::unlock` when the entry is
+// dropped so anything it removed still reaches the listener.
+struct Locked<'a, K: Clone + Hash + Eq + 'a, V: Send + 'a> {
+  cache: &'a LruCache<K, V>,
+  inner: Option<RwLockWriteGuard<'a, Inner<K, V>>>,
+  now: u64,
+}
+
+// `inner` must already have removed `k` if it had expired.
+pub fn new<'a, K: Clone + Hash + Eq, V: Send>(
+    cache: &'a LruCache<K, V>,
+    inner: RwLockWriteGuard<'a, Inner<K, V>>,
+    k: K,
+    now: u64) -> Entry<'a, K, V> {
+  at(Locked { cache, inner: Some(inner), now }, k)
+}
+
+fn at<K: Clone + Hash + Eq, V: Send>(lock: Locked<'_, K, V>, k: K) -> Entry<'_, K, V> {
+  match lock.inner().map.get(&k) {
+    Some(&slot) => Entry::Occupied(OccupiedEntry { lock, slot }),
+    None => Entry::Vacant(VacantEntry { lock, key: k }),
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send> Locked<'a, K, V> {
+  fn inner(&self) -> &Inner<K, V> {
+    self.inner.as_ref().unwrap()
+  }
+
+  fn inner_mut(&mut self) -> &mut Inner<K, V> {
+    self.inner.as_mut().unwrap()
+  }
+
+  // Returns the new value and the replaced one. Like `LruCache::insert` an
+  // entry heavier than `max_weight` is not stored.
+  fn insert(&mut self, k: K, v: V) -> (Arc<V>, Option<Arc<V>>) {
+    let now = self.now;
+    let arc = Arc::new(v);
+    let entry = self.cache.new_entry(k.clone(), arc.clone(), now, None);
+    let replaced = self.inner_mut().insert(entry, now).unwrap_or_else(|(_, replaced)| replaced);
+    // Still holding the lock, see `LruCache::record_absent`.
+    self.cache.forget_absent(&k);
+    (arc, replaced)
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send> Drop for Locked<'a, K, V> {
+  fn drop(&mut self) {
+    self.cache.unlock(self.inner.take().unwrap());
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send> Entry<'a, K, V> {
+  pub fn key(&self) -> &K {
+    match *self {
+      Entry::Occupied(ref entry) => entry.key(),
+      Entry::Vacant(ref entry) => entry.key(),
+    }
+  }
+
+  pub fn or_insert(self, v: V) -> Arc<V> {
+    self.or_insert_with(|| v)
+  }
+
+  pub fn or_insert_with<F>(self, f: F) -> Arc<V>
+      where F: FnOnce() -> V {
+    match self {
+      Entry::Occupied(entry) => entry.get(),
+      Entry::Vacant(entry) => entry.insert(f()),
+    }
+  }
+
+  // Replaces an existing value with one computed from it. The entry becomes
+  // vacant if the new value is too heavy to be stored.
+  pub fn and_modify<F>(self, f: F) -> Entry<'a, K, V>
+      where F: FnOnce(&V) -> V {
+    match self {
+      Entry::Occupied(entry) => {
+        let v = f(&entry.get());
+        let k = entry.key().clone();
+        let mut lock = entry.lock;
+        lock.insert(k.clone(), v);
+        at(lock, k)
+      },
+      Entry::Vacant(entry) => Entry::Vacant(entry),
+    }
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send> OccupiedEntry<'a, K, V> {
+  pub fn key(&self) -> &K {
+    &self.lock.inner().entries[self.slot].as_ref().unwrap().key
+  }
+
+  pub fn get(&self) -> Arc<V> {
+    self.lock.inner().entries[self.slot].as_ref().unwrap().arc.clone()
+  }
+
+  // Returns the replaced value.
+  pub fn insert(self, v: V) -> Arc<V> {
+    let k = self.key().clone();
+    let mut lock = self.lock;
+    lock.insert(k, v).1.expect("an occupied entry has not expired")
+  }
+
+  pub fn remove(self) -> Arc<V> {
+    let mut lock = self.lock;
+    let inner = lock.inner_mut();
+    let entry = inner.release(self.slot);
+    inner.map.remove(&entry.key);
+    inner.removed(entry.key, entry.arc.clone(), RemovalCause::Explicit);
+    entry.arc
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send> VacantEntry<'a, K, V> {
+  pub fn key(&self) -> &K {
+    &self.key
+  }
+
+  // Evicts as `LruCache::insert` would to make room.
+  pub fn insert(self, v: V) -> Arc<V> {
+    let mut lock = self.lock;
+    lock.insert(self.key, v).0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{ Arc, Mutex };
+  use { LruCache, RemovalCause };
+  use super::*;
+
+  #[test]
+  fn or_insert_and_modify() {
+    let cash = LruCache::with_limit(2);
+    assert_eq!(*cash.entry("a").or_insert(1), 1);
+    assert_eq!(*cash.entry("a").or_insert(2), 1);
+    assert_eq!(*cash.entry("a").and_modify(|v| v + 10).or_insert(0), 11);
+    assert_eq!(*cash.entry("b").and_modify(|v| v + 10).or_insert_with(|| 5), 5);
+    assert_eq!(cash.get(&"a").map(|a| *a), Some(11));
+
+    match cash.entry("b") {
+      Entry::Occupied(entry) => assert_eq!(*entry.remove(), 5),
+      Entry::Vacant(_) => panic!("b should be present"),
+    }
+    assert!(!cash.contains_key(&"b"));
+  }
+
+  #[test]
+  fn respects_eviction() {
+    let removed = Arc::new(Mutex::new(Vec::new()));
+    let cash = {
+      let removed = removed.clone();
+      LruCache::builder(2)
+        .removal_listener(move |k, _, cause| removed.lock().unwrap().push((k, cause)))
+        .build()
+    };
+    cash.insert(0u8, 0u8);
+    cash.insert(1, 1);
+    cash.get(&0);
+    cash.entry(2).or_insert(2);
+    assert_eq!(*removed.lock().unwrap(), vec![(1, RemovalCause::Capacity)]);
+    assert_eq!(cash.len(), 2);
+    assert!(cash.contains_key(&0));
+  }
+}
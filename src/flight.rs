@@ -0,0 +1,80 @@
+use std::any::Any;
+use std::mem;
+use std::sync::{ Arc, Condvar, Mutex };
+use std::task::Waker;
+#[cfg(feature = "async")]
+use std::task::Poll;
+
+pub type SharedError = Arc<dyn Any + Send + Sync>;
+
+// A load in progress for one key. The thread or task that creates it runs the
+// loader without holding the cache lock, every other one missing the same key
+// waits here for the result.
+pub struct Flight<V> {
+  state: Mutex<State<V>>,
+  changed: Condvar,
+}
+
+enum State<V> {
+  // With the wakers of tasks waiting on the load.
+  Loading(Vec<Waker>),
+  Done(Arc<V>),
+  Failed(Option<SharedError>),
+}
+
+impl<V> Flight<V> {
+  pub fn new() -> Flight<V> {
+    Flight {
+      state: Mutex::new(State::Loading(Vec::new())),
+      changed: Condvar::new(),
+    }
+  }
+
+  // Returns `Err(None)` if the leader gave up without sharing an error (its
+  // loader panicked, it was cancelled or it wants waiters to retry), in which
+  // case the caller should retry and may become the new leader.
+  pub fn wait(&self) -> Result<Arc<V>, Option<SharedError>> {
+    let mut state = self.state.lock().unwrap();
+    loop {
+      match *state {
+        State::Loading(_) => state = self.changed.wait(state).unwrap(),
+        State::Done(ref arc) => return Ok(arc.clone()),
+        State::Failed(ref error) => return Err(error.clone()),
+      }
+    }
+  }
+
+  // Like `wait`, but registers `waker` to be woken when the load finishes
+  // instead of blocking.
+  #[cfg(feature = "async")]
+  pub fn poll(&self, waker: &Waker) -> Poll<Result<Arc<V>, Option<SharedError>>> {
+    match *self.state.lock().unwrap() {
+      State::Loading(ref mut wakers) => {
+        if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+          wakers.push(waker.clone());
+        }
+        Poll::Pending
+      },
+      State::Done(ref arc) => Poll::Ready(Ok(arc.clone())),
+      State::Failed(ref error) => Poll::Ready(Err(error.clone())),
+    }
+  }
+
+  pub fn complete(&self, arc: Arc<V>) {
+    self.finish(State::Done(arc));
+  }
+
+  pub fn fail(&self, error: Option<SharedError>) {
+    self.finish(State::Failed(error));
+  }
+
+  fn finish(&self, state: State<V>) {
+    let previous = mem::replace(&mut *self.state.lock().unwrap(), state);
+    self.changed.notify_all();
+    if let State::Loading(wakers) = previous {
+      for waker in wakers {
+        waker.wake();
+      }
+    }
+  }
+}
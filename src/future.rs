@@ -0,0 +1,182 @@
+use std::future::Future;
+use std::hash::Hash;
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ Context, Poll };
+
+use { Claim, LruCache };
+use flight::Flight;
+
+// The future returned by `LruCache::get_with`. It only locks the cache inside
+// `poll`, never across a suspension.
+pub struct GetWith<'a, K: Clone + Hash + Eq + 'a, V: Send + 'a, F, Fut> {
+  cache: &'a LruCache<K, V>,
+  key: K,
+  loader: Option<F>,
+  state: State<V, Fut>,
+}
+
+enum State<V, Fut> {
+  Start,
+  // Running the loader, waiters are woken when it finishes or this future is
+  // dropped.
+  Leading(Arc<Flight<V>>, Pin<Box<Fut>>),
+  Waiting(Arc<Flight<V>>),
+  Done,
+}
+
+// Nothing is pinned in place, the loader's future is boxed.
+impl<'a, K: Clone + Hash + Eq, V: Send, F, Fut> Unpin for GetWith<'a, K, V, F, Fut> {
+}
+
+pub fn new<K: Clone + Hash + Eq, V: Send, F, Fut>(cache: &LruCache<K, V>, k: K, f: F) -> GetWith<'_, K, V, F, Fut> {
+  GetWith { cache, key: k, loader: Some(f), state: State::Start }
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send, F, Fut> Future for GetWith<'a, K, V, F, Fut>
+    where F: FnOnce() -> Fut, Fut: Future<Output = V> {
+  type Output = Arc<V>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Arc<V>> {
+    let this = &mut *self;
+    loop {
+      let next = match this.state {
+        State::Start => match this.cache.claim(&this.key) {
+          Claim::Hit(arc) => {
+            this.state = State::Done;
+            return Poll::Ready(arc);
+          },
+          Claim::Lead(flight) => {
+            let f = this.loader.take().expect("a future only leads one load");
+            State::Leading(flight, Box::pin(f()))
+          },
+          Claim::Wait(flight) => State::Waiting(flight),
+        },
+        State::Leading(_, ref mut loading) => match loading.as_mut().poll(cx) {
+          Poll::Ready(v) => {
+            if let State::Leading(flight, _) = mem::replace(&mut this.state, State::Done) {
+              let arc = Arc::new(v);
+              this.cache.finish_load(&this.key, arc.clone(), flight);
+              return Poll::Ready(arc);
+            }
+            unreachable!();
+          },
+          Poll::Pending => return Poll::Pending,
+        },
+        State::Waiting(ref flight) => match flight.poll(cx.waker()) {
+          Poll::Ready(Ok(arc)) => {
+            this.state = State::Done;
+            return Poll::Ready(arc);
+          },
+          // The leader gave up, try again and maybe take over.
+          Poll::Ready(Err(_)) => State::Start,
+          Poll::Pending => return Poll::Pending,
+        },
+        State::Done => panic!("`GetWith` polled after completion"),
+      };
+      this.state = next;
+    }
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send, F, Fut> Drop for GetWith<'a, K, V, F, Fut> {
+  fn drop(&mut self) {
+    if let State::Leading(flight, loading) = mem::replace(&mut self.state, State::Done) {
+      drop(loading);
+      self.cache.abandon_load(&self.key, flight, None);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::future::Future;
+  use std::pin::Pin;
+  use std::sync::Arc;
+  use std::sync::atomic::{ AtomicUsize, Ordering };
+  use std::task::{ Context, Poll, Wake, Waker };
+
+  use LruCache;
+
+  // Pending once, then ready with `value`.
+  struct Yield<V>(bool, Option<V>);
+
+  impl<V: Unpin> Future for Yield<V> {
+    type Output = V;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<V> {
+      if self.0 {
+        Poll::Ready(self.1.take().unwrap())
+      } else {
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+      }
+    }
+  }
+
+  struct Wakes(AtomicUsize);
+
+  impl Wake for Wakes {
+    fn wake(self: Arc<Self>) {
+      self.0.fetch_add(1, Ordering::SeqCst);
+    }
+  }
+
+  fn poll<F: Future + Unpin>(future: &mut F, waker: &Waker) -> Poll<F::Output> {
+    Pin::new(future).poll(&mut Context::from_waker(waker))
+  }
+
+  #[test]
+  fn single_flight() {
+    let cash = LruCache::with_limit(2);
+    let calls = AtomicUsize::new(0);
+    let load = || {
+      calls.fetch_add(1, Ordering::SeqCst);
+      Yield(false, Some(7u32))
+    };
+    let wakes = Arc::new(Wakes(AtomicUsize::new(0)));
+    let waker = Waker::from(wakes.clone());
+
+    let mut leader = cash.get_with(0u8, load);
+    let mut waiter = cash.get_with(0u8, load);
+    assert!(poll(&mut leader, &waker).is_pending());
+    assert!(poll(&mut waiter, &waker).is_pending());
+    let before = wakes.0.load(Ordering::SeqCst);
+    let loaded = match poll(&mut leader, &waker) {
+      Poll::Ready(arc) => arc,
+      Poll::Pending => panic!("the loader is ready"),
+    };
+    assert!(wakes.0.load(Ordering::SeqCst) > before);
+    match poll(&mut waiter, &waker) {
+      Poll::Ready(arc) => assert!(Arc::ptr_eq(&arc, &loaded)),
+      Poll::Pending => panic!("the load has finished"),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(cash.get(&0).map(|a| *a), Some(7));
+  }
+
+  #[test]
+  fn dropped_leader_hands_over() {
+    let cash = LruCache::with_limit(2);
+    let wakes = Arc::new(Wakes(AtomicUsize::new(0)));
+    let waker = Waker::from(wakes.clone());
+
+    let mut leader = cash.get_with(0u8, || Yield(false, Some(1u32)));
+    let mut waiter = cash.get_with(0u8, || Yield(false, Some(2u32)));
+    assert!(poll(&mut leader, &waker).is_pending());
+    assert!(poll(&mut waiter, &waker).is_pending());
+    let before = wakes.0.load(Ordering::SeqCst);
+    drop(leader);
+    assert!(wakes.0.load(Ordering::SeqCst) > before);
+
+    // Takes over and runs its own loader.
+    assert!(poll(&mut waiter, &waker).is_pending());
+    match poll(&mut waiter, &waker) {
+      Poll::Ready(arc) => assert_eq!(*arc, 2),
+      Poll::Pending => panic!("the loader is ready"),
+    }
+    assert!(cash.inner.read().unwrap().loading.is_empty());
+  }
+}
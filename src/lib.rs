@@ -1,68 +1,1095 @@
-extern crate time;
+mod arc_cache;
+mod builder;
+mod clock;
+mod entry;
+mod flight;
+#[cfg(feature = "async")]
+mod future;
+mod list;
+pub mod policy;
+mod read_buffer;
+mod refresh;
+mod sharded;
+mod stats;
+mod store;
 
-use std::sync::{ Arc, Mutex };
-use std::hash::Hash;
+use std::sync::{ Arc, RwLock, RwLockReadGuard, RwLockWriteGuard };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::hash::{ BuildHasher, Hash };
 use std::borrow::Borrow;
+use std::mem;
+use std::slice;
+use std::fmt;
+use std::error::Error;
+use std::convert::Infallible;
+use std::cmp::Reverse;
 use std::collections::{ hash_map, HashMap };
+use std::time::{ Duration, Instant };
+use std::vec;
+
+use flight::{ Flight, SharedError };
+use policy::EvictionPolicy;
+use read_buffer::ReadBuffers;
+use stats::StatsCounter;
+
+pub use arc_cache::ArcCache;
+pub use builder::Builder;
+pub use clock::{ Clock, MockClock, SystemClock };
+pub use entry::{ Entry, OccupiedEntry, VacantEntry };
+#[cfg(feature = "async")]
+pub use future::GetWith;
+pub use refresh::RefreshingCache;
+pub use sharded::ShardedLruCache;
+pub use stats::CacheStats;
+pub use store::{ CacheStore, MemoryStore, StoreBackedCache, WriteMode };
 
 pub struct LruCache<K, V: Send> {
+  clock: Arc<dyn Clock>,
+  epoch: Instant,
+  listener: Option<Box<Listener<K, V>>>,
+  weigher: Option<Box<Weigher<K, V>>>,
+  stats: Arc<StatsCounter>,
+  reads: ReadBuffers,
+  inner: RwLock<Inner<K, V>>,
+  // Keys a loader found no value for, see `Builder::negative_cache`.
+  negative: Option<Box<LruCache<K, ()>>>,
+}
+
+type Listener<K, V> = dyn Fn(K, Arc<V>, RemovalCause) + Send + Sync;
+type Weigher<K, V> = dyn Fn(&K, &V) -> u64 + Send + Sync;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RemovalCause {
+  // Evicted to make room for another entry.
+  Capacity,
+  // Found to be past its time-to-live.
+  Expired,
+  // Removed by `remove`.
+  Explicit,
+  // Overwritten by an `insert` for the same key.
+  Replaced,
+  // Removed by `clear`.
+  Cleared,
+}
+
+// Returned by `lookup`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Lookup<V> {
+  Present(Arc<V>),
+  // A loader recently found no value for the key, see
+  // `get_or_insert_with_optional`.
+  Absent,
+  NotCached,
+}
+
+// A loader found no value, shared with the threads waiting on it.
+struct NotFound;
+
+// Returned by `try_insert` for an entry that weighs more than the cache's
+// whole `max_weight`. Any previous value for the key is still removed, as it
+// would have been by a successful insert.
+#[derive(Debug)]
+pub struct TooHeavy<K, V> {
+  pub key: K,
+  pub value: V,
+  pub weight: u64,
+  pub replaced: Option<Arc<V>>,
+}
+
+impl<K, V> fmt::Display for TooHeavy<K, V> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "entry weighing {} is heavier than the cache's maximum weight", self.weight)
+  }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> Error for TooHeavy<K, V> {
+}
+
+struct Inner<K, V> {
   limit: usize,
-  map: Mutex<HashMap<K, CacheEntry<V>>>,
+  max_weight: u64,
+  weight: u64,
+  expire_after_write: Option<u64>,
+  expire_after_access: Option<u64>,
+  map: HashMap<K, usize>,
+  entries: Vec<Option<CacheEntry<K, V>>>,
+  free: Vec<usize>,
+  policy: Box<dyn EvictionPolicy>,
+  loading: HashMap<K, Arc<Flight<V>>>,
+  // The next `CacheEntry::sequence`.
+  sequence: u64,
+  // Removed entries waiting to be passed to the listener once the lock is
+  // released. Only collected when there is a listener.
+  notify: bool,
+  removals: Vec<(K, Arc<V>, RemovalCause)>,
+  stats: Arc<StatsCounter>,
 }
 
-struct CacheEntry<V> {
-  last_access: u64,
+// Timestamps are nanoseconds since the cache's epoch.
+struct CacheEntry<K, V> {
+  key: K,
   arc: Arc<V>,
+  hash: u64,
+  weight: u64,
+  written: u64,
+  // Updated by `get` under the shared lock.
+  accessed: AtomicU64,
+  // Stamped when the entry is stored and when a read of it reaches the
+  // policy, to order entries read within the same clock tick.
+  sequence: u64,
+  ttl: Option<u64>,
+}
+
+fn nanos(duration: Duration) -> u64 {
+  duration.as_secs().saturating_mul(1_000_000_000).saturating_add(u64::from(duration.subsec_nanos()))
 }
 
 impl<K: Clone + Hash + Eq, V: Send> LruCache<K, V> {
   pub fn with_limit(limit: usize) -> LruCache<K, V> {
-    assert!(limit != 0);
+    Builder::new(limit).build()
+  }
+
+  pub fn builder(limit: usize) -> Builder<K, V> {
+    Builder::new(limit)
+  }
+
+  fn new(
+      limit: usize,
+      clock: Arc<dyn Clock>,
+      listener: Option<Box<Listener<K, V>>>,
+      weigher: Option<Box<Weigher<K, V>>>,
+      stats: bool,
+      policy: Box<dyn EvictionPolicy>) -> LruCache<K, V> {
+    let notify = listener.is_some();
+    let stats = Arc::new(StatsCounter::new(stats));
     LruCache {
-      limit: limit,
-      map: Mutex::new(HashMap::with_capacity(limit))
+      epoch: clock.now(),
+      clock,
+      listener,
+      weigher,
+      stats: stats.clone(),
+      reads: ReadBuffers::new(),
+      inner: RwLock::new(Inner {
+        limit,
+        max_weight: u64::MAX,
+        weight: 0,
+        expire_after_write: None,
+        expire_after_access: None,
+        map: HashMap::with_capacity(limit),
+        entries: Vec::with_capacity(limit),
+        free: Vec::new(),
+        policy,
+        loading: HashMap::new(),
+        sequence: 0,
+        notify,
+        removals: Vec::new(),
+        stats,
+      }),
+      negative: None,
     }
   }
 
-  pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<Arc<V>>
-      where K: Borrow<Q>, Q: Hash + Eq {
-    if let Some(entry) = self.map.lock().unwrap().get_mut(k) {
-      entry.last_access = time::precise_time_ns();
-      Some(entry.arc.clone())
-    } else {
-      None
+  fn now(&self) -> u64 {
+    nanos(self.clock.now().duration_since(self.epoch))
+  }
+
+  fn read(&self) -> RwLockReadGuard<'_, Inner<K, V>> {
+    self.inner.read().unwrap()
+  }
+
+  // Applies the accesses recorded by `get` first, so the eviction policy is
+  // up to date before anything is inserted or removed.
+  fn write(&self) -> RwLockWriteGuard<'_, Inner<K, V>> {
+    let mut inner = self.inner.write().unwrap();
+    self.drain(&mut inner);
+    inner
+  }
+
+  fn drain(&self, inner: &mut Inner<K, V>) {
+    let Inner { ref mut entries, ref mut policy, ref mut sequence, .. } = *inner;
+    self.reads.drain(|slot| {
+      if let Some(ref mut entry) = entries[slot] {
+        policy.access(slot, entry.hash);
+        entry.sequence = *sequence;
+        *sequence += 1;
+      }
+    });
+  }
+
+  // Every write lock that may have removed entries must be released through
+  // here, so the listener runs without the lock held and can use the cache
+  // itself.
+  fn unlock(&self, mut inner: RwLockWriteGuard<'_, Inner<K, V>>) {
+    let removals = mem::take(&mut inner.removals);
+    drop(inner);
+    if let Some(ref listener) = self.listener {
+      for (k, arc, cause) in removals {
+        listener(k, arc, cause);
+      }
+    }
+  }
+
+  // Expired entries are treated as misses and removed.
+  pub fn get<Q>(&self, k: &Q) -> Option<Arc<V>>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    let now = self.now();
+    let arc = self.read_hit(k, now).unwrap_or_else(|()| {
+      let mut inner = self.write();
+      let arc = inner.get(k, now);
+      self.unlock(inner);
+      arc
+    });
+    self.stats.lookup(&arc);
+    arc
+  }
+
+  // Looks `k` up under the shared lock, recording a hit for the eviction
+  // policy to apply later. `Err` if the entry has expired and has to be
+  // removed under the exclusive lock.
+  fn read_hit<Q>(&self, k: &Q, now: u64) -> Result<Option<Arc<V>>, ()>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    let (arc, full) = {
+      let inner = self.read();
+      let slot = match inner.map.get(k) {
+        Some(&slot) => slot,
+        None => return Ok(None),
+      };
+      let entry = inner.entries[slot].as_ref().unwrap();
+      if inner.is_expired(entry, now) {
+        return Err(());
+      }
+      entry.accessed.store(now, Ordering::Relaxed);
+      (entry.arc.clone(), self.reads.record(slot))
+    };
+    // Only drain if nobody else holds the lock, a busy writer drains anyway.
+    if full {
+      if let Ok(mut inner) = self.inner.try_write() {
+        self.drain(&mut inner);
+      }
     }
+    Ok(Some(arc))
+  }
+
+  fn new_entry(&self, k: K, arc: Arc<V>, now: u64, ttl: Option<u64>) -> CacheEntry<K, V> {
+    let weight = self.weigher.as_ref().map_or(1, |weigher| weigher(&k, &arc));
+    CacheEntry { key: k, arc, hash: 0, weight, written: now, accessed: AtomicU64::new(now), sequence: 0, ttl }
   }
 
+  // An entry heavier than `max_weight` is not stored, see `try_insert`.
   pub fn insert(&self, k: K, v: V) -> Option<Arc<V>> {
-    let new_entry = CacheEntry {
-      last_access: time::precise_time_ns(),
-      arc: Arc::new(v),
+    self.insert_entry(k, v, None).unwrap_or_else(|too_heavy| too_heavy.replaced)
+  }
+
+  // Like `insert`, but the entry expires after `ttl` instead of the cache's
+  // `expire_after_write` setting.
+  pub fn insert_with_ttl(&self, k: K, v: V, ttl: Duration) -> Option<Arc<V>> {
+    self.insert_entry(k, v, Some(nanos(ttl))).unwrap_or_else(|too_heavy| too_heavy.replaced)
+  }
+
+  // Like `insert`, but hands the entry back if it could never fit.
+  pub fn try_insert(&self, k: K, v: V) -> Result<Option<Arc<V>>, TooHeavy<K, V>> {
+    self.insert_entry(k, v, None)
+  }
+
+  fn insert_entry(&self, k: K, v: V, ttl: Option<u64>) -> Result<Option<Arc<V>>, TooHeavy<K, V>> {
+    let now = self.now();
+    let entry = self.new_entry(k.clone(), Arc::new(v), now, ttl);
+    let mut inner = self.write();
+    let result = inner.insert(entry, now);
+    self.unlock(inner);
+    self.forget_absent(&k);
+    result.map_err(|(entry, replaced)| TooHeavy {
+      key: entry.key,
+      value: Arc::try_unwrap(entry.arc).ok().expect("the rejected value was never shared"),
+      weight: entry.weight,
+      replaced,
+    })
+  }
+
+  // Like `get` for each key, all under one lock.
+  pub fn get_many<Q>(&self, keys: &[Q]) -> Vec<Option<Arc<V>>>
+      where K: Borrow<Q>, Q: Hash + Eq {
+    self.get_each(keys)
+  }
+
+  // `get_many` for keys that are not in a slice, as when a sharded cache
+  // splits them up.
+  fn get_each<'q, Q, I>(&self, keys: I) -> Vec<Option<Arc<V>>>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq + 'q, I: IntoIterator<Item = &'q Q> {
+    let now = self.now();
+    let mut inner = self.write();
+    let found: Vec<_> = keys.into_iter().map(|k| inner.get(k, now)).collect();
+    self.unlock(inner);
+    for arc in &found {
+      self.stats.lookup(arc);
+    }
+    found
+  }
+
+  // Like `insert` for each entry in order, all under one lock. With more
+  // entries than the limit the first ones are evicted to make room for the
+  // last, as they would be by separate inserts.
+  pub fn insert_many<I>(&self, entries: I)
+      where I: IntoIterator<Item = (K, V)> {
+    let now = self.now();
+    let entries: Vec<_> = entries.into_iter().map(|(k, v)| self.new_entry(k, Arc::new(v), now, None)).collect();
+    let keys: Vec<K> = entries.iter().map(|entry| entry.key.clone()).collect();
+    let mut inner = self.write();
+    for entry in entries {
+      let _ = inner.insert(entry, now);
+    }
+    self.unlock(inner);
+    for k in &keys {
+      self.forget_absent(k);
+    }
+  }
+
+  // Like `remove` for each key, all under one lock.
+  pub fn remove_many<Q>(&self, keys: &[Q]) -> Vec<Option<Arc<V>>>
+      where K: Borrow<Q>, Q: Hash + Eq {
+    self.remove_each(keys)
+  }
+
+  // `remove_many` for keys that are not in a slice, see `get_each`.
+  fn remove_each<'q, Q, I>(&self, keys: I) -> Vec<Option<Arc<V>>>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq + 'q, I: IntoIterator<Item = &'q Q> {
+    let keys: Vec<&Q> = keys.into_iter().collect();
+    let mut inner = self.write();
+    let removed = keys.iter().map(|&k| inner.remove(k)).collect();
+    self.unlock(inner);
+    for k in keys {
+      self.forget_absent(k);
+    }
+    removed
+  }
+
+  // Like `get_or_insert_with` for each key, but the keys that miss are
+  // loaded by a single call to `f`, which returns their values in the same
+  // order. Keys another thread is already loading are waited for instead.
+  // `f` is called again for any of those whose loader gave up. The loaded
+  // values are inserted in order, see `insert_many`.
+  pub fn get_or_insert_many_with<F>(&self, keys: &[K], mut f: F) -> Vec<Arc<V>>
+      where F: FnMut(&[K]) -> Vec<V> {
+    let loaded = self.load_many(keys, |missing| Ok::<_, Infallible>(f(missing).into_iter().map(Some).collect()));
+    match loaded {
+      Ok(found) => found.into_iter().map(|arc| arc.expect("every key was found or loaded")).collect(),
+      Err(never) => match never {},
+    }
+  }
+
+  // The core of `get_or_insert_many_with`, where `f` may also find there is
+  // no value for some keys, or fail and abandon all of its loads.
+  fn load_many<E, F>(&self, keys: &[K], f: F) -> Result<Vec<Option<Arc<V>>>, E>
+      where F: FnMut(&[K]) -> Result<Vec<Option<V>>, E> {
+    load_many(slice::from_ref(self), keys, |_| 0, f)
+  }
+
+  // Like `insert`, for a value the caller keeps a handle to.
+  fn insert_arc(&self, k: K, arc: Arc<V>) -> Option<Arc<V>> {
+    let now = self.now();
+    let entry = self.new_entry(k.clone(), arc, now, None);
+    let mut inner = self.write();
+    let replaced = inner.insert(entry, now).unwrap_or_else(|(_, replaced)| replaced);
+    self.unlock(inner);
+    self.forget_absent(&k);
+    replaced
+  }
+
+  // On a miss `f` runs without the cache locked. Concurrent misses on the
+  // same key wait for that single call instead of running their own `f`.
+  pub fn get_or_insert_with<F>(&self, k: K, f: F) -> Arc<V>
+      where F: FnOnce() -> V {
+    match self.load(k, || Ok::<V, Infallible>(f()), |_| None, |_| None) {
+      Ok(arc) => arc,
+      Err(never) => match never {},
+    }
+  }
+
+  // Like `get_or_insert_with`, but nothing is cached if `f` fails. Threads
+  // that were waiting on a failed load retry it with their own `f`.
+  pub fn try_get_or_insert_with<E, F>(&self, k: K, f: F) -> Result<Arc<V>, E>
+      where F: FnOnce() -> Result<V, E> {
+    self.load(k, f, |_| None, |_| None)
+  }
+
+  // Like `try_get_or_insert_with`, but threads that were waiting on a failed
+  // load get the same error instead of retrying. Waiters only see errors from
+  // leaders that also used this method with the same error type.
+  pub fn try_get_or_insert_with_shared<E, F>(&self, k: K, f: F) -> Result<Arc<V>, Arc<E>>
+      where F: FnOnce() -> Result<V, E>, E: Send + Sync + 'static {
+    self.load(
+      k,
+      || f().map_err(Arc::new),
+      |error| Some(error.clone() as SharedError),
+      |error| error.downcast().ok())
+  }
+
+  // Like `get_or_insert_with`, but `f` may find there is no value for `k`.
+  // With a `Builder::negative_cache` that is remembered for a while, and
+  // until then a miss returns `None` without calling `f`. Threads waiting on
+  // a load that finds nothing get `None` too.
+  pub fn get_or_insert_with_optional<F>(&self, k: K, f: F) -> Option<Arc<V>>
+      where F: FnOnce() -> Option<V> {
+    if let Ok(Some(arc)) = self.read_hit(&k, self.now()) {
+      self.stats.lookup(&Some(()));
+      return Some(arc);
+    }
+    if self.negative.as_ref().is_some_and(|negative| negative.get(&k).is_some()) {
+      return None;
+    }
+    let absent = k.clone();
+    let found = || f().ok_or_else(|| {
+      self.record_absent(absent);
+      NotFound
+    });
+    self.load(
+      k,
+      found,
+      |_| Some(Arc::new(NotFound) as SharedError),
+      |error| error.downcast::<NotFound>().ok().map(|_| NotFound)).ok()
+  }
+
+  // Like `get`, but tells keys known to be absent apart from ones that are
+  // not cached at all.
+  pub fn lookup<Q>(&self, k: &Q) -> Lookup<V>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    match self.get(k) {
+      Some(arc) => Lookup::Present(arc),
+      None if self.negative.as_ref().is_some_and(|negative| negative.get(k).is_some()) => Lookup::Absent,
+      None => Lookup::NotCached,
+    }
+  }
+
+  // Remembers that a loader found no value for `k`, unless one was stored
+  // while it ran. Writes only forget absent keys after storing their value,
+  // so holding the lock while checking means neither can be missed.
+  fn record_absent(&self, k: K) {
+    if let Some(ref negative) = self.negative {
+      let now = self.now();
+      let inner = self.read();
+      let stored = inner.map.get(&k)
+        .and_then(|&slot| inner.entries[slot].as_ref())
+        .is_some_and(|entry| !inner.is_expired(entry, now));
+      if !stored {
+        negative.insert(k, ());
+      }
+    }
+  }
+
+  // Called after the value for `k` has been written, see `record_absent`.
+  fn forget_absent<Q>(&self, k: &Q)
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    if let Some(ref negative) = self.negative {
+      negative.remove(k);
+    }
+  }
+
+  fn load<E, F, S, R>(&self, k: K, f: F, share: S, receive: R) -> Result<Arc<V>, E>
+      where F: FnOnce() -> Result<V, E>, S: FnOnce(&E) -> Option<SharedError>, R: Fn(SharedError) -> Option<E> {
+    let mut f = Some(f);
+    let mut share = Some(share);
+    loop {
+      match self.claim(&k) {
+        Claim::Hit(arc) => return Ok(arc),
+        Claim::Lead(flight) => {
+          let guard = LoadGuard { cache: self, key: &k, flight: Some(flight) };
+          let f = f.take().expect("a thread only leads one load");
+          return match f() {
+            Ok(v) => {
+              let arc = Arc::new(v);
+              guard.complete(arc.clone());
+              Ok(arc)
+            },
+            Err(error) => {
+              let share = share.take().expect("a thread only leads one load");
+              guard.fail(share(&error));
+              Err(error)
+            },
+          };
+        },
+        Claim::Wait(flight) => {
+          match flight.wait() {
+            Ok(arc) => return Ok(arc),
+            Err(Some(error)) => if let Some(error) = receive(error) {
+              return Err(error);
+            },
+            Err(None) => (),
+          }
+        },
+      }
+    }
+  }
+
+  // Looks `k` up for a loader, on a miss either joining the load already in
+  // progress or starting a new one that the caller must lead.
+  fn claim(&self, k: &K) -> Claim<V> {
+    let now = self.now();
+    if let Ok(Some(arc)) = self.read_hit(k, now) {
+      self.stats.lookup(&Some(()));
+      return Claim::Hit(arc);
+    }
+
+    let mut inner = self.write();
+    let found = inner.get(k, now);
+    self.stats.lookup(&found);
+    let claim = match found {
+      Some(arc) => Claim::Hit(arc),
+      None => match inner.loading.entry(k.clone()) {
+        hash_map::Entry::Occupied(entry) => Claim::Wait(entry.get().clone()),
+        hash_map::Entry::Vacant(entry) => Claim::Lead(entry.insert(Arc::new(Flight::new())).clone()),
+      },
     };
+    self.unlock(inner);
+    claim
+  }
 
-    let mut map = self.map.lock().unwrap();
-    if map.len() == self.limit {
-      let oldest = map.iter().min_by_key(|&(_, entry)| entry.last_access).unwrap().0.clone();
-      map.remove(&oldest);
+  // Stores the value loaded by the leader of `flight` and hands it to the
+  // waiters. A value written for `k` while the loader ran superseded the
+  // load, and is kept instead.
+  fn finish_load(&self, k: &K, arc: Arc<V>, flight: Arc<Flight<V>>) {
+    {
+      let now = self.now();
+      let entry = self.new_entry(k.clone(), arc.clone(), now, None);
+      let mut inner = self.write();
+      if inner.leads(k, &flight) {
+        let _ = inner.insert(entry, now);
+      }
+      self.unlock(inner);
     }
+    self.forget_absent(k);
+    flight.complete(arc);
+  }
+
+  // The leader of `flight` gave up. Without an error the waiters retry and
+  // one of them takes the load over.
+  fn abandon_load(&self, k: &K, flight: Arc<Flight<V>>, error: Option<SharedError>) {
+    // Also reached while unwinding from a panicking loader, so tolerates a
+    // poisoned lock.
+    if let Ok(mut inner) = self.inner.write() {
+      if inner.leads(k, &flight) {
+        inner.loading.remove(k);
+      }
+    }
+    flight.fail(error);
+  }
+
+  // Locks the cache until the entry is dropped. Counts as a `get` of `k`.
+  pub fn entry(&self, k: K) -> Entry<'_, K, V> {
+    let now = self.now();
+    let mut inner = self.write();
+    let found = inner.get(&k, now);
+    self.stats.lookup(&found);
+    entry::new(self, inner, k, now)
+  }
 
-    let old_entry = match map.entry(k) {
-      hash_map::Entry::Occupied(mut entry) => {
-        Some(entry.insert(new_entry))
+  // Like `get_or_insert_with`, but the loader is async. Concurrent misses on
+  // the same key, from tasks or threads, wait for a single load. If the task
+  // leading the load drops its future, one of the waiting tasks takes over.
+  // The cache is never locked across an await.
+  #[cfg(feature = "async")]
+  pub fn get_with<F, Fut>(&self, k: K, f: F) -> GetWith<'_, K, V, F, Fut>
+      where F: FnOnce() -> Fut, Fut: std::future::Future<Output = V> {
+    future::new(self, k, f)
+  }
+
+  // Sets the value of `k` to `f` of the current one in a single step, or
+  // removes it if `f` returns `None`. Counts as a `get` of `k`. `f` runs with
+  // the cache locked, so must not use the cache itself.
+  pub fn compute<F>(&self, k: K, f: F) -> Option<Arc<V>>
+      where F: FnOnce(Option<&Arc<V>>) -> Option<V> {
+    let now = self.now();
+    let mut inner = self.write();
+    let current = inner.get(&k, now);
+    self.stats.lookup(&current);
+    let arc = match f(current.as_ref()) {
+      Some(v) => {
+        let arc = Arc::new(v);
+        let entry = self.new_entry(k.clone(), arc.clone(), now, None);
+        let _ = inner.insert(entry, now);
+        // Still holding the lock, see `record_absent`.
+        self.forget_absent(&k);
+        Some(arc)
+      },
+      None => {
+        inner.remove(&k);
+        None
       },
-      hash_map::Entry::Vacant(entry) => {
-        entry.insert(new_entry);
+    };
+    self.unlock(inner);
+    arc
+  }
+
+  // Replaces the value of `k` with `v` only if it is still `expected`,
+  // compared with `Arc::ptr_eq`. Returns whether it was replaced.
+  pub fn replace_if(&self, k: K, expected: &Arc<V>, v: V) -> bool {
+    let now = self.now();
+    let mut inner = self.write();
+    let current = inner.map.get(&k)
+      .and_then(|&slot| inner.entries[slot].as_ref())
+      .is_some_and(|entry| !inner.is_expired(entry, now) && Arc::ptr_eq(&entry.arc, expected));
+    if current {
+      let entry = self.new_entry(k, Arc::new(v), now, None);
+      let _ = inner.insert(entry, now);
+    }
+    self.unlock(inner);
+    current
+  }
+
+  pub fn remove<Q>(&self, k: &Q) -> Option<Arc<V>>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    let mut inner = self.write();
+    let arc = inner.remove(k);
+    self.unlock(inner);
+    self.forget_absent(k);
+    arc
+  }
+
+  pub fn contains_key<Q>(&self, k: &Q) -> bool
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    self.peek(k).is_some()
+  }
+
+  // Like `get`, but is not reported to the eviction policy as an access
+  // and does not count as an access for `expire_after_access`.
+  pub fn peek<Q>(&self, k: &Q) -> Option<Arc<V>>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    let now = self.now();
+    let inner = self.read();
+    inner.map.get(k)
+      .and_then(|&slot| inner.entries[slot].as_ref())
+      .filter(|entry| !inner.is_expired(entry, now))
+      .map(|entry| entry.arc.clone())
+  }
+
+  // Copies of the live entries, most recently used first. Like `peek` this
+  // does not count as an access of any of them.
+  pub fn snapshot(&self) -> Vec<(K, Arc<V>)> {
+    self.snapshot_accessed().into_iter().map(|(k, v, _)| (k, v)).collect()
+  }
+
+  // A `snapshot` with the time each entry was last used, which a sharded
+  // cache merges its shards by.
+  fn snapshot_accessed(&self) -> Vec<(K, Arc<V>, Instant)> {
+    let now = self.now();
+    // The write lock applies any pending reads to the eviction policy first.
+    let inner = self.write();
+    inner.recency().into_iter()
+      .filter_map(|slot| inner.entries[slot].as_ref())
+      .filter(|entry| !inner.is_expired(entry, now))
+      .map(|entry| {
+        let accessed = self.epoch + Duration::from_nanos(entry.accessed.load(Ordering::Relaxed));
+        (entry.key.clone(), entry.arc.clone(), accessed)
+      })
+      .collect()
+  }
+
+  // Iterates over a `snapshot`, the cache is not locked while iterating.
+  pub fn iter(&self) -> vec::IntoIter<(K, Arc<V>)> {
+    self.snapshot().into_iter()
+  }
+
+  // The keys of a `snapshot`.
+  pub fn keys(&self) -> Vec<K> {
+    self.snapshot().into_iter().map(|(k, _)| k).collect()
+  }
+
+  // Removes every entry `f` returns false for, all under one lock. Expired
+  // entries are removed without being passed to `f`. `f` runs with the cache
+  // locked, so must not use the cache itself.
+  pub fn retain<F>(&self, mut f: F)
+      where F: FnMut(&K, &Arc<V>) -> bool {
+    let now = self.now();
+    let mut inner = self.write();
+    for slot in 0..inner.entries.len() {
+      let cause = match inner.entries[slot] {
+        Some(ref entry) if inner.is_expired(entry, now) => RemovalCause::Expired,
+        Some(ref entry) if !f(&entry.key, &entry.arc) => RemovalCause::Explicit,
+        _ => continue,
+      };
+      let entry = inner.release(slot);
+      inner.map.remove(&entry.key);
+      inner.removed(entry.key, entry.arc, cause);
+    }
+    self.unlock(inner);
+  }
+
+  // Nanoseconds since the live value of `k` was written.
+  fn age<Q>(&self, k: &Q) -> Option<u64>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    let now = self.now();
+    let inner = self.read();
+    inner.map.get(k)
+      .and_then(|&slot| inner.entries[slot].as_ref())
+      .filter(|entry| !inner.is_expired(entry, now))
+      .map(|entry| now.saturating_sub(entry.written))
+  }
+
+  // Includes expired entries that have not been removed yet.
+  pub fn len(&self) -> usize {
+    self.read().map.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.read().limit
+  }
+
+  // Shrinking evicts entries straight away until there are at most `limit`.
+  pub fn set_limit(&self, limit: usize) {
+    assert!(limit != 0);
+    let mut inner = self.write();
+    inner.limit = limit;
+    inner.policy.set_capacity(limit);
+    while inner.map.len() > limit {
+      match inner.evict() {
+        Some(entry) => inner.removed(entry.key, entry.arc, RemovalCause::Capacity),
+        None => break,
+      }
+    }
+    let additional = limit.saturating_sub(inner.map.len());
+    inner.map.reserve(additional);
+    let additional = limit.saturating_sub(inner.entries.len());
+    inner.entries.reserve(additional);
+    self.unlock(inner);
+  }
+
+  // The total weight of the entries, which is their count without a weigher.
+  pub fn weight(&self) -> u64 {
+    self.read().weight
+  }
+
+  // All zero unless the cache was built with `record_stats`.
+  pub fn stats(&self) -> CacheStats {
+    self.stats.snapshot()
+  }
+
+  pub fn reset_stats(&self) {
+    self.stats.reset();
+  }
+
+  pub fn clear(&self) {
+    let mut inner = self.write();
+    inner.map.clear();
+    inner.loading.clear();
+    inner.free.clear();
+    inner.weight = 0;
+    inner.policy.clear();
+    let entries = mem::take(&mut inner.entries);
+    for entry in entries.into_iter().flatten() {
+      inner.removed(entry.key, entry.arc, RemovalCause::Cleared);
+    }
+    self.unlock(inner);
+    if let Some(ref negative) = self.negative {
+      negative.clear();
+    }
+  }
+}
+
+// `LruCache::load_many` over several caches, with `shard` picking the cache
+// each key belongs to. Each cache is locked once per round, and the misses in
+// all of them are loaded by a single call to `f`.
+fn load_many<K, V, E, F, P>(caches: &[LruCache<K, V>], keys: &[K], shard: P, mut f: F) -> Result<Vec<Option<Arc<V>>>, E>
+    where K: Clone + Hash + Eq, V: Send, F: FnMut(&[K]) -> Result<Vec<Option<V>>, E>, P: Fn(&K) -> usize {
+  let mut found: Vec<Option<Arc<V>>> = vec![None; keys.len()];
+  let mut pending: Vec<usize> = (0..keys.len()).collect();
+  while !pending.is_empty() {
+    let mut groups = vec![Vec::new(); caches.len()];
+    for &i in &pending {
+      groups[shard(&keys[i])].push(i);
+    }
+    let mut led: Vec<Vec<(K, Arc<Flight<V>>)>> = caches.iter().map(|_| Vec::new()).collect();
+    // For each pending key, its cache and the index of its load in `led`, or
+    // another thread's load.
+    let mut sources = Vec::new();
+    for (c, (cache, group)) in caches.iter().zip(groups).enumerate() {
+      if group.is_empty() {
+        continue;
+      }
+      let led = &mut led[c];
+      let now = cache.now();
+      let mut inner = cache.write();
+      for i in group {
+        let hit = inner.get(&keys[i], now);
+        cache.stats.lookup(&hit);
+        if let Some(arc) = hit {
+          found[i] = Some(arc);
+          continue;
+        }
+        let source = match inner.loading.entry(keys[i].clone()) {
+          hash_map::Entry::Occupied(entry) => {
+            // A key repeated in `keys` is already being led by this call.
+            led.iter()
+              .position(|(_, flight)| Arc::ptr_eq(flight, entry.get()))
+              .map(|j| (c, j))
+              .ok_or_else(|| entry.get().clone())
+          },
+          hash_map::Entry::Vacant(entry) => {
+            let flight = entry.insert(Arc::new(Flight::new())).clone();
+            led.push((keys[i].clone(), flight));
+            Ok((c, led.len() - 1))
+          },
+        };
+        sources.push((i, source));
+      }
+      cache.unlock(inner);
+    }
+
+    let missing: Vec<K> = led.iter().flatten().map(|(k, _)| k.clone()).collect();
+    let loaded: Vec<Vec<Option<Arc<V>>>> = if missing.is_empty() {
+      Vec::new()
+    } else {
+      let guards: Vec<_> = caches.iter().zip(led).map(|(cache, flights)| BatchGuard { cache, flights }).collect();
+      let values = f(&missing)?;
+      assert_eq!(values.len(), missing.len(), "the loader must return a result for every key");
+      let mut values = values.into_iter();
+      guards.into_iter().map(|guard| match guard.flights.len() {
+        0 => Vec::new(),
+        count => guard.complete(values.by_ref().take(count).collect()),
+      }).collect()
+    };
+
+    pending.clear();
+    for (i, source) in sources {
+      match source {
+        Ok((c, j)) => found[i] = loaded[c][j].clone(),
+        Err(flight) => match flight.wait() {
+          Ok(arc) => found[i] = Some(arc),
+          Err(_) => pending.push(i),
+        },
+      }
+    }
+  }
+  Ok(found)
+}
+
+impl<K: Clone + Hash + Eq, V> Inner<K, V> {
+  fn is_expired(&self, entry: &CacheEntry<K, V>, now: u64) -> bool {
+    let expired = |since: u64, ttl: Option<u64>| ttl.is_some_and(|ttl| now >= since.saturating_add(ttl));
+    expired(entry.written, entry.ttl.or(self.expire_after_write))
+      || expired(entry.accessed.load(Ordering::Relaxed), self.expire_after_access)
+  }
+
+  // Occupied slots, most recently used first.
+  fn recency(&self) -> Vec<usize> {
+    self.policy.recency().unwrap_or_else(|| {
+      let mut slots: Vec<usize> = self.map.values().cloned().collect();
+      slots.sort_by_key(|&slot| {
+        let entry = self.entries[slot].as_ref().unwrap();
+        Reverse((entry.accessed.load(Ordering::Relaxed), entry.sequence))
+      });
+      slots
+    })
+  }
+
+  fn get<Q>(&mut self, k: &Q, now: u64) -> Option<Arc<V>>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    let slot = *self.map.get(k)?;
+    if self.is_expired(self.entries[slot].as_ref().unwrap(), now) {
+      self.map.remove(k);
+      let entry = self.release(slot);
+      self.removed(entry.key, entry.arc, RemovalCause::Expired);
+      return None;
+    }
+    let entry = self.entries[slot].as_mut().unwrap();
+    self.policy.access(slot, entry.hash);
+    entry.accessed.store(now, Ordering::Relaxed);
+    entry.sequence = self.sequence;
+    self.sequence += 1;
+    Some(entry.arc.clone())
+  }
+
+  // Whether `flight` is still the load for `k`, rather than having been
+  // superseded by a write.
+  fn leads(&self, k: &K, flight: &Arc<Flight<V>>) -> bool {
+    self.loading.get(k).is_some_and(|loading| Arc::ptr_eq(loading, flight))
+  }
+
+  // Also supersedes any load in progress for `k`, see `insert`.
+  fn remove<Q>(&mut self, k: &Q) -> Option<Arc<V>>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    self.loading.remove(k);
+    let slot = self.map.remove(k)?;
+    let entry = self.release(slot);
+    self.removed(entry.key, entry.arc.clone(), RemovalCause::Explicit);
+    Some(entry.arc)
+  }
+
+  // Returns the replaced value, unless it had already expired. An entry that
+  // is too heavy to ever fit is handed back along with the replaced value.
+  //
+  // Any load in progress for the key is superseded: its loader's value is
+  // older than this one, so it is not stored when it finishes.
+  #[allow(clippy::type_complexity)]
+  fn insert(&mut self, mut new_entry: CacheEntry<K, V>, now: u64)
+      -> Result<Option<Arc<V>>, (CacheEntry<K, V>, Option<Arc<V>>)> {
+    self.loading.remove(&new_entry.key);
+    let replaced = self.map.remove(&new_entry.key).and_then(|slot| {
+      let old_entry = self.release(slot);
+      if self.is_expired(&old_entry, now) {
+        self.removed(old_entry.key, old_entry.arc, RemovalCause::Expired);
         None
+      } else {
+        self.removed(old_entry.key, old_entry.arc.clone(), RemovalCause::Replaced);
+        Some(old_entry.arc)
+      }
+    });
+
+    if new_entry.weight > self.max_weight {
+      return Err((new_entry, replaced));
+    }
+
+    new_entry.hash = self.map.hasher().hash_one(&new_entry.key);
+    self.policy.inserting(new_entry.hash);
+    // A policy with no victim leaves the cache over its limit instead of
+    // spinning here.
+    while self.map.len() >= self.limit || self.weight.saturating_add(new_entry.weight) > self.max_weight {
+      match self.evict() {
+        Some(entry) => self.removed(entry.key, entry.arc, RemovalCause::Capacity),
+        None => break,
+      }
+    }
+    self.push(new_entry);
+    self.stats.insert();
+
+    Ok(replaced)
+  }
+
+  fn push(&mut self, mut entry: CacheEntry<K, V>) {
+    entry.sequence = self.sequence;
+    self.sequence += 1;
+    let key = entry.key.clone();
+    let hash = entry.hash;
+    self.weight += entry.weight;
+    let slot = match self.free.pop() {
+      Some(slot) => {
+        self.entries[slot] = Some(entry);
+        slot
+      },
+      None => {
+        self.entries.push(Some(entry));
+        self.entries.len() - 1
       },
     };
+    self.policy.insert(slot, hash);
+    self.map.insert(key, slot);
+  }
+
+  fn evict(&mut self) -> Option<CacheEntry<K, V>> {
+    let slot = self.policy.victim()?;
+    let entry = self.release(slot);
+    self.map.remove(&entry.key);
+    Some(entry)
+  }
 
-    old_entry.map(|entry| entry.arc)
+  fn removed(&mut self, k: K, arc: Arc<V>, cause: RemovalCause) {
+    self.stats.removal(cause);
+    if self.notify {
+      self.removals.push((k, arc, cause));
+    }
+  }
+
+  // Frees the slot and unlinks it, the caller is responsible for the map.
+  fn release(&mut self, slot: usize) -> CacheEntry<K, V> {
+    self.policy.remove(slot);
+    self.free.push(slot);
+    let entry = self.entries[slot].take().unwrap();
+    self.weight -= entry.weight;
+    entry
+  }
+}
+
+enum Claim<V> {
+  Hit(Arc<V>),
+  Lead(Arc<Flight<V>>),
+  Wait(Arc<Flight<V>>),
+}
+
+// Owned by the thread running a loader. If the loader unwinds before the
+// value is stored the flight fails without an error, so a waiting thread can
+// take over.
+struct LoadGuard<'a, K: Clone + Hash + Eq + 'a, V: Send + 'a> {
+  cache: &'a LruCache<K, V>,
+  key: &'a K,
+  flight: Option<Arc<Flight<V>>>,
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send> LoadGuard<'a, K, V> {
+  fn complete(mut self, arc: Arc<V>) {
+    self.cache.finish_load(self.key, arc, self.flight.take().unwrap());
+  }
+
+  fn fail(mut self, error: Option<SharedError>) {
+    self.cache.abandon_load(self.key, self.flight.take().unwrap(), error);
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send> Drop for LoadGuard<'a, K, V> {
+  fn drop(&mut self) {
+    if let Some(flight) = self.flight.take() {
+      self.cache.abandon_load(self.key, flight, None);
+    }
+  }
+}
+
+// The loads claimed by `load_many`. If the loader unwinds or fails they all
+// fail without an error, like with `LoadGuard`.
+struct BatchGuard<'a, K: Clone + Hash + Eq + 'a, V: Send + 'a> {
+  cache: &'a LruCache<K, V>,
+  flights: Vec<(K, Arc<Flight<V>>)>,
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send> BatchGuard<'a, K, V> {
+  // Keys without a value are abandoned, so their waiters retry.
+  fn complete(mut self, values: Vec<Option<V>>) -> Vec<Option<Arc<V>>> {
+    assert_eq!(values.len(), self.flights.len(), "the loader must return a result for every key");
+    let flights = mem::take(&mut self.flights);
+    let arcs: Vec<Option<Arc<V>>> = values.into_iter().map(|v| v.map(Arc::new)).collect();
+    let now = self.cache.now();
+    let entries: Vec<_> = flights.iter().zip(&arcs).map(|((k, _), arc)| {
+      arc.as_ref().map(|arc| self.cache.new_entry(k.clone(), arc.clone(), now, None))
+    }).collect();
+    let mut inner = self.cache.write();
+    for (entry, (k, flight)) in entries.into_iter().zip(&flights) {
+      if inner.leads(k, flight) {
+        match entry {
+          Some(entry) => {
+            let _ = inner.insert(entry, now);
+          },
+          None => {
+            inner.loading.remove(k);
+          },
+        }
+      }
+    }
+    self.cache.unlock(inner);
+    for ((k, flight), arc) in flights.into_iter().zip(&arcs) {
+      match *arc {
+        Some(ref arc) => {
+          self.cache.forget_absent(&k);
+          flight.complete(arc.clone());
+        },
+        None => flight.fail(None),
+      }
+    }
+    arcs
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V: Send> Drop for BatchGuard<'a, K, V> {
+  fn drop(&mut self) {
+    for (k, flight) in mem::take(&mut self.flights) {
+      self.cache.abandon_load(&k, flight, None);
+    }
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use std::sync::Mutex;
 
   #[test]
   fn smoke() {
@@ -89,4 +1116,582 @@ mod tests {
     cash.insert(5, 5);
     assert_eq!(cash.get(&1).map(|a| *a), None);
   }
+
+  #[test]
+  fn replace_promotes() {
+    let cash = LruCache::with_limit(3);
+    cash.insert(0u8, 0u8);
+    cash.insert(1, 1);
+    cash.insert(2, 2);
+    assert_eq!(cash.insert(0, 10).map(|a| *a), Some(0));
+    cash.insert(3, 3);
+    assert_eq!(cash.get(&0).map(|a| *a), Some(10));
+    assert_eq!(cash.get(&1), None);
+  }
+
+  #[test]
+  fn exact_recency_order() {
+    let cash = LruCache::with_limit(100);
+    for i in 0..100u32 {
+      cash.insert(i, i);
+    }
+    // Accesses within the same clock tick must still be strictly ordered.
+    for i in (0..100u32).rev() {
+      cash.get(&i);
+    }
+    for i in 100..150u32 {
+      cash.insert(i, i);
+    }
+    for i in 0..50u32 {
+      assert_eq!(cash.get(&i).map(|a| *a), Some(i));
+    }
+    for i in 50..100u32 {
+      assert_eq!(cash.get(&i), None);
+    }
+  }
+
+  #[test]
+  fn concurrent_reads_and_writes() {
+    use std::thread;
+
+    let cash = Arc::new(LruCache::with_limit(64));
+    let threads: Vec<_> = (0..4u32).map(|t| {
+      let cash = cash.clone();
+      thread::spawn(move || {
+        for i in 0..2000u32 {
+          let k = (i * 7 + t) % 128;
+          if i % 4 == 0 {
+            cash.insert(k, k);
+          } else if let Some(v) = cash.get(&k) {
+            assert_eq!(*v, k);
+          }
+        }
+      })
+    }).collect();
+    for thread in threads {
+      thread.join().unwrap();
+    }
+    assert!(cash.len() <= 64);
+    for i in 1000..1064u32 {
+      cash.insert(i, i);
+    }
+    assert_eq!(cash.len(), 64);
+    assert!((1000..1064u32).all(|i| cash.contains_key(&i)));
+  }
+
+  #[test]
+  fn compute_and_replace_if() {
+    let cash = LruCache::with_limit(2);
+    let add = |v: Option<&Arc<u32>>| Some(v.map_or(1, |v| **v + 1));
+    assert_eq!(cash.compute("a", add).map(|a| *a), Some(1));
+    assert_eq!(cash.compute("a", add).map(|a| *a), Some(2));
+    assert_eq!(cash.compute("a", |_| None), None);
+    assert!(!cash.contains_key(&"a"));
+
+    let old = cash.compute("b", add).unwrap();
+    assert!(cash.replace_if("b", &old, 10));
+    assert!(!cash.replace_if("b", &old, 20));
+    assert_eq!(cash.get(&"b").map(|a| *a), Some(10));
+    assert!(!cash.replace_if("c", &old, 30));
+    assert!(!cash.contains_key(&"c"));
+  }
+
+  #[test]
+  fn snapshot_and_retain() {
+    let cash = LruCache::with_limit(4);
+    for i in 0..4u32 {
+      cash.insert(i, i * 10);
+    }
+    cash.get(&1);
+    cash.peek(&2);
+    assert_eq!(cash.keys(), vec![1, 3, 2, 0]);
+    assert_eq!(cash.snapshot()[0], (1, Arc::new(10)));
+    assert_eq!(cash.keys(), vec![1, 3, 2, 0]);
+    assert_eq!(cash.iter().map(|(_, v)| *v).sum::<u32>(), 60);
+
+    cash.retain(|&k, _| k % 2 == 1);
+    assert_eq!(cash.keys(), vec![1, 3]);
+    assert_eq!(cash.len(), 2);
+  }
+
+  #[test]
+  fn snapshot_order_within_a_tick() {
+    // Without `Lru` the order comes from access times, which all match here.
+    let cash = LruCache::builder(8).clock(MockClock::new()).eviction_policy(policy::Fifo::new()).build();
+    for i in 0..6u32 {
+      cash.insert(i, i);
+    }
+    cash.get(&2);
+    cash.get_many(&[4]);
+    assert_eq!(cash.keys(), vec![4, 2, 5, 3, 1, 0]);
+  }
+
+  #[test]
+  fn set_limit() {
+    let cash = LruCache::with_limit(4);
+    for i in 0..4u32 {
+      cash.insert(i, i);
+    }
+    cash.get(&0);
+    cash.set_limit(2);
+    assert_eq!(cash.capacity(), 2);
+    assert_eq!(cash.keys(), vec![0, 3]);
+    cash.insert(4, 4);
+    assert_eq!(cash.keys(), vec![4, 0]);
+
+    cash.set_limit(3);
+    cash.insert(5, 5);
+    assert_eq!(cash.keys(), vec![5, 4, 0]);
+  }
+
+  #[test]
+  fn negative_cache() {
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    let clock = MockClock::new();
+    let cash = LruCache::builder(4)
+      .clock(clock.clone())
+      .negative_cache(2, Duration::from_secs(1))
+      .build();
+    let calls = AtomicUsize::new(0);
+    let missing = || {
+      calls.fetch_add(1, Ordering::SeqCst);
+      None
+    };
+    assert_eq!(cash.lookup(&0u8), Lookup::NotCached);
+    assert_eq!(cash.get_or_insert_with_optional(0u8, missing), None);
+    assert_eq!(cash.get_or_insert_with_optional(0u8, missing), None);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(cash.lookup(&0), Lookup::Absent);
+    assert_eq!(cash.len(), 0);
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(cash.lookup(&0), Lookup::NotCached);
+    assert_eq!(cash.get_or_insert_with_optional(0u8, missing), None);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    cash.insert(0, 10u32);
+    assert_eq!(cash.lookup(&0), Lookup::Present(Arc::new(10)));
+    cash.remove(&0);
+    assert_eq!(cash.lookup(&0), Lookup::NotCached);
+    assert_eq!(cash.get_or_insert_with_optional(0u8, || Some(20)).map(|a| *a), Some(20));
+  }
+
+  #[test]
+  fn bulk_operations() {
+    let cash = LruCache::with_limit(3);
+    cash.insert_many((0..5u32).map(|i| (i, i)));
+    assert_eq!(cash.keys(), vec![4, 3, 2]);
+    let found: Vec<_> = cash.get_many(&[2, 0, 4]).into_iter().map(|arc| arc.map(|a| *a)).collect();
+    assert_eq!(found, vec![Some(2), None, Some(4)]);
+    assert_eq!(cash.keys(), vec![4, 2, 3]);
+    let removed: Vec<_> = cash.remove_many(&[3, 5]).into_iter().map(|arc| arc.map(|a| *a)).collect();
+    assert_eq!(removed, vec![Some(3), None]);
+    assert_eq!(cash.len(), 2);
+  }
+
+  #[test]
+  fn get_or_insert_many_with() {
+    let cash = LruCache::with_limit(4);
+    cash.insert(1u32, 10u32);
+    let mut calls = Vec::new();
+    let values = cash.get_or_insert_many_with(&[0, 1, 2, 0], |missing| {
+      calls.push(missing.to_vec());
+      missing.iter().map(|k| k * 10).collect()
+    });
+    assert_eq!(values.iter().map(|a| **a).collect::<Vec<_>>(), vec![0, 10, 20, 0]);
+    assert!(Arc::ptr_eq(&values[0], &values[3]));
+    assert_eq!(calls, vec![vec![0, 2]]);
+    assert_eq!(cash.len(), 3);
+    assert!(cash.inner.read().unwrap().loading.is_empty());
+  }
+
+  #[test]
+  fn insert_during_negative_load() {
+    use std::sync::mpsc;
+    use std::thread;
+
+    let cash = Arc::new(LruCache::builder(4).negative_cache(4, Duration::from_secs(60)).build());
+    let (started, wait_started) = mpsc::channel();
+    let (release, wait_release) = mpsc::channel::<()>();
+    let loader = {
+      let cash = cash.clone();
+      thread::spawn(move || {
+        cash.get_or_insert_with_optional(0u8, || {
+          started.send(()).unwrap();
+          wait_release.recv().unwrap();
+          None
+        }).is_none()
+      })
+    };
+    wait_started.recv().unwrap();
+    cash.insert(0, 5u32);
+    release.send(()).unwrap();
+    assert!(loader.join().unwrap());
+    assert_eq!(cash.get_or_insert_with_optional(0, || None).map(|a| *a), Some(5));
+    assert!(cash.negative.as_ref().unwrap().is_empty());
+  }
+
+  #[test]
+  fn map_api() {
+    let cash = LruCache::with_limit(3);
+    assert!(cash.is_empty());
+    assert_eq!(cash.capacity(), 3);
+    cash.insert("a".to_owned(), 0u8);
+    cash.insert("b".to_owned(), 1);
+    cash.insert("c".to_owned(), 2);
+    assert_eq!(cash.len(), 3);
+    assert!(cash.contains_key("a"));
+    assert_eq!(cash.remove("b").map(|a| *a), Some(1));
+    assert_eq!(cash.remove("b"), None);
+    assert!(!cash.contains_key("b"));
+    assert_eq!(cash.len(), 2);
+    cash.clear();
+    assert!(cash.is_empty());
+    assert_eq!(cash.get("a"), None);
+    cash.insert("d".to_owned(), 3);
+    assert_eq!(cash.get("d").map(|a| *a), Some(3));
+  }
+
+  #[test]
+  fn peek_keeps_order() {
+    let cash = LruCache::with_limit(2);
+    cash.insert(0u8, 0u8);
+    cash.insert(1, 1);
+    assert_eq!(cash.peek(&0).map(|a| *a), Some(0));
+    assert!(cash.contains_key(&0));
+    cash.insert(2, 2);
+    assert_eq!(cash.peek(&0), None);
+    assert_eq!(cash.peek(&1).map(|a| *a), Some(1));
+  }
+
+  #[test]
+  fn get_or_insert_with() {
+    let cash = LruCache::with_limit(2);
+    assert_eq!(*cash.get_or_insert_with(0u8, || 0u8), 0);
+    assert_eq!(*cash.get_or_insert_with(0, || panic!("cached")), 0);
+    assert_eq!(cash.get(&0).map(|a| *a), Some(0));
+  }
+
+  #[test]
+  fn single_flight() {
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    let cash = Arc::new(LruCache::with_limit(2));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let barrier = Arc::new(Barrier::new(8));
+    let threads: Vec<_> = (0..8).map(|_| {
+      let (cash, calls, barrier) = (cash.clone(), calls.clone(), barrier.clone());
+      thread::spawn(move || {
+        barrier.wait();
+        *cash.get_or_insert_with(0u8, || {
+          calls.fetch_add(1, Ordering::SeqCst);
+          thread::sleep(Duration::from_millis(50));
+          7u8
+        })
+      })
+    }).collect();
+    for thread in threads {
+      assert_eq!(thread.join().unwrap(), 7);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn loader_panic_hands_over() {
+    use std::panic::{ self, AssertUnwindSafe };
+
+    let cash = LruCache::with_limit(2);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+      cash.get_or_insert_with(0u8, || panic!("loader failed"))
+    }));
+    assert!(result.is_err());
+    assert!(cash.inner.read().unwrap().loading.is_empty());
+    assert_eq!(*cash.get_or_insert_with(0, || 1u8), 1);
+  }
+
+  #[test]
+  fn insert_supersedes_load() {
+    use std::sync::mpsc;
+    use std::thread;
+
+    let cash = Arc::new(LruCache::with_limit(2));
+    let (started, wait_started) = mpsc::channel();
+    let (release, wait_release) = mpsc::channel::<()>();
+    let loader = {
+      let cash = cash.clone();
+      thread::spawn(move || {
+        *cash.get_or_insert_with(0u8, || {
+          started.send(()).unwrap();
+          wait_release.recv().unwrap();
+          1u8
+        })
+      })
+    };
+    wait_started.recv().unwrap();
+    cash.insert(0, 2);
+    release.send(()).unwrap();
+    assert_eq!(loader.join().unwrap(), 1);
+    assert_eq!(cash.get(&0).map(|a| *a), Some(2));
+    assert!(cash.inner.read().unwrap().loading.is_empty());
+  }
+
+  #[test]
+  fn try_get_or_insert_with() {
+    let cash = LruCache::with_limit(2);
+    assert_eq!(cash.try_get_or_insert_with(0u8, || Err("io")), Err("io"));
+    assert!(!cash.contains_key(&0));
+    assert_eq!(cash.try_get_or_insert_with(0, || Ok::<u8, ()>(1)).map(|a| *a), Ok(1));
+    assert_eq!(cash.try_get_or_insert_with(0, || Err(())).map(|a| *a), Ok(1));
+  }
+
+  #[test]
+  fn failed_load_waiters() {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    // `shared` waiters get the leader's error, the others retry with their
+    // own loader.
+    for &shared in &[true, false] {
+      let cash = Arc::new(LruCache::with_limit(2));
+      let (started, leading) = mpsc::channel();
+      let leader = {
+        let cash = cash.clone();
+        thread::spawn(move || {
+          cash.try_get_or_insert_with_shared(0u8, || {
+            started.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            Err::<u8, _>("parse")
+          }).map(|a| *a)
+        })
+      };
+      leading.recv().unwrap();
+      let waited = if shared {
+        cash.try_get_or_insert_with_shared(0, || Ok(2)).map(|a| *a).map_err(|e| *e)
+      } else {
+        cash.try_get_or_insert_with(0, || Ok(2)).map(|a| *a).map_err(|e: Arc<&str>| *e)
+      };
+      assert_eq!(leader.join().unwrap(), Err(Arc::new("parse")));
+      if shared {
+        assert_eq!(waited, Err("parse"));
+        assert!(!cash.contains_key(&0));
+      } else {
+        assert_eq!(waited, Ok(2));
+        assert_eq!(cash.get(&0).map(|a| *a), Some(2));
+      }
+    }
+  }
+
+  #[test]
+  fn expire_after_write() {
+    let clock = MockClock::new();
+    let cash = LruCache::builder(4)
+      .expire_after_write(Duration::from_secs(20))
+      .clock(clock.clone())
+      .build();
+    cash.insert(0u8, 0u8);
+    cash.insert_with_ttl(1, 1, Duration::from_secs(60));
+    clock.advance(Duration::from_secs(19));
+    assert_eq!(cash.get(&0).map(|a| *a), Some(0));
+    clock.advance(Duration::from_secs(1));
+    assert!(!cash.contains_key(&0));
+    assert_eq!(cash.len(), 2);
+    assert_eq!(cash.get(&0), None);
+    assert_eq!(cash.len(), 1);
+    assert_eq!(cash.get(&1).map(|a| *a), Some(1));
+    assert_eq!(cash.insert(1, 2).map(|a| *a), Some(1));
+  }
+
+  #[test]
+  fn expire_after_access() {
+    let clock = MockClock::new();
+    let cash = LruCache::builder(4)
+      .expire_after_access(Duration::from_secs(40))
+      .clock(clock.clone())
+      .build();
+    cash.insert(0u8, 0u8);
+    cash.insert(1, 1);
+    for _ in 0..4 {
+      clock.advance(Duration::from_secs(15));
+      assert_eq!(cash.get(&0).map(|a| *a), Some(0));
+    }
+    assert_eq!(cash.peek(&0).map(|a| *a), Some(0));
+    assert_eq!(cash.get(&1), None);
+    assert_eq!(cash.insert(1, 1), None);
+  }
+
+  #[test]
+  fn removal_listener() {
+    let clock = MockClock::new();
+    let removed = Arc::new(Mutex::new(Vec::new()));
+    let cash = {
+      let removed = removed.clone();
+      LruCache::builder(2)
+        .expire_after_write(Duration::from_secs(10))
+        .clock(clock.clone())
+        .removal_listener(move |k, arc: Arc<u8>, cause| removed.lock().unwrap().push((k, *arc, cause)))
+        .build()
+    };
+    cash.insert(0u8, 0u8);
+    cash.insert(0, 1);
+    cash.insert(1, 1);
+    cash.insert(2, 2);
+    cash.remove(&1);
+    clock.advance(Duration::from_secs(10));
+    cash.get(&2);
+    cash.insert(3, 3);
+    cash.clear();
+    assert_eq!(*removed.lock().unwrap(), vec![
+      (0, 0, RemovalCause::Replaced),
+      (0, 1, RemovalCause::Capacity),
+      (1, 1, RemovalCause::Explicit),
+      (2, 2, RemovalCause::Expired),
+      (3, 3, RemovalCause::Cleared),
+    ]);
+  }
+
+  #[test]
+  fn listener_can_use_cache() {
+    let cash = Arc::new(Mutex::new(None::<Arc<LruCache<u8, u8>>>));
+    let inner = {
+      let cash = cash.clone();
+      Arc::new(LruCache::builder(1)
+        .removal_listener(move |k, _, _| {
+          let cash = cash.lock().unwrap().clone().unwrap();
+          assert!(!cash.contains_key(&k));
+          assert_eq!(cash.len(), 1);
+        })
+        .build())
+    };
+    *cash.lock().unwrap() = Some(inner.clone());
+    inner.insert(0, 0);
+    inner.insert(1, 1);
+    *cash.lock().unwrap() = None;
+  }
+
+  #[test]
+  fn weighted() {
+    let cash = LruCache::builder(100)
+      .max_weight(10)
+      .weigher(|_: &u8, v: &String| v.len() as u64)
+      .build();
+    cash.insert(0, "aaa".to_owned());
+    cash.insert(1, "bbb".to_owned());
+    cash.insert(2, "ccc".to_owned());
+    assert_eq!(cash.weight(), 9);
+    cash.insert(3, "ddddddd".to_owned());
+    assert_eq!(cash.weight(), 10);
+    assert_eq!(cash.len(), 2);
+    assert!(cash.contains_key(&2));
+    cash.insert(2, "c".to_owned());
+    assert_eq!(cash.weight(), 8);
+
+    let too_heavy = cash.try_insert(4, "eeeeeeeeeee".to_owned()).unwrap_err();
+    assert_eq!((too_heavy.key, too_heavy.weight), (4, 11));
+    assert_eq!(too_heavy.value, "eeeeeeeeeee");
+    assert_eq!(cash.len(), 2);
+    assert_eq!(cash.insert(3, "fffffffffff".to_owned()).map(|a| a.len()), Some(7));
+    assert_eq!(cash.len(), 1);
+    assert_eq!(cash.weight(), 1);
+  }
+
+  #[test]
+  fn stats() {
+    let clock = MockClock::new();
+    let cash = LruCache::builder(2)
+      .expire_after_write(Duration::from_secs(10))
+      .clock(clock.clone())
+      .record_stats()
+      .build();
+    cash.insert(0u8, 0u8);
+    cash.insert(0, 1);
+    cash.get(&0);
+    cash.get(&1);
+    cash.get_or_insert_with(1, || 1);
+    cash.insert(2, 2);
+    clock.advance(Duration::from_secs(10));
+    cash.get(&2);
+    assert_eq!(cash.stats(), CacheStats {
+      hits: 1,
+      misses: 3,
+      inserts: 4,
+      replacements: 1,
+      evictions: 1,
+      expirations: 1,
+    });
+    cash.reset_stats();
+    assert_eq!(cash.stats(), CacheStats::default());
+
+    let cash = LruCache::with_limit(2);
+    cash.insert(0u8, 0u8);
+    cash.get(&0);
+    assert_eq!(cash.stats(), CacheStats::default());
+  }
+
+  #[test]
+  fn lfu_policy() {
+    let cash = LruCache::builder(3).eviction_policy(policy::Lfu::new()).build();
+    cash.insert(0u8, 0u8);
+    cash.insert(1, 1);
+    cash.insert(2, 2);
+    cash.get(&0);
+    cash.get(&0);
+    cash.get(&2);
+    cash.insert(3, 3);
+    assert!(!cash.contains_key(&1));
+    cash.insert(4, 4);
+    assert!(!cash.contains_key(&3));
+    assert!(cash.contains_key(&0));
+    assert!(cash.contains_key(&2));
+  }
+
+  #[test]
+  fn reuses_slots() {
+    let cash = LruCache::with_limit(2);
+    for i in 0..100u32 {
+      cash.insert(i, i);
+    }
+    assert_eq!(cash.inner.read().unwrap().entries.len(), 2);
+    assert_eq!(cash.get(&98).map(|a| *a), Some(98));
+    assert_eq!(cash.get(&99).map(|a| *a), Some(99));
+  }
+
+  // Never offers a victim, like a policy that has lost track of its slots.
+  struct NoVictim;
+
+  impl EvictionPolicy for NoVictim {
+    fn insert(&mut self, _: usize, _: u64) {
+    }
+
+    fn access(&mut self, _: usize, _: u64) {
+    }
+
+    fn remove(&mut self, _: usize) {
+    }
+
+    fn victim(&mut self) -> Option<usize> {
+      None
+    }
+
+    fn clear(&mut self) {
+    }
+  }
+
+  #[test]
+  fn policy_without_victim() {
+    let cash = LruCache::builder(2).eviction_policy(NoVictim).build();
+    for i in 0..4u32 {
+      cash.insert(i, i);
+    }
+    assert_eq!(cash.len(), 4);
+    assert_eq!(cash.get(&0).map(|a| *a), Some(0));
+
+    // Nothing can be evicted, so the cache stays over the new limit.
+    cash.set_limit(1);
+    assert_eq!((cash.capacity(), cash.len()), (1, 4));
+  }
 }
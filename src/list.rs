@@ -0,0 +1,103 @@
+// Doubly linked lists threaded through slot indices, so several lists can
+// share one set of links as long as each slot is in at most one of them.
+
+const NIL: usize = !0;
+
+#[derive(Clone, Copy)]
+struct Link {
+  prev: usize,
+  next: usize,
+}
+
+pub struct Links {
+  links: Vec<Link>,
+}
+
+impl Links {
+  pub fn with_capacity(capacity: usize) -> Links {
+    Links { links: Vec::with_capacity(capacity) }
+  }
+
+  pub fn prev(&self, slot: usize) -> Option<usize> {
+    let prev = self.links[slot].prev;
+    if prev == NIL { None } else { Some(prev) }
+  }
+
+  fn ensure(&mut self, slot: usize) {
+    if slot >= self.links.len() {
+      self.links.resize(slot + 1, Link { prev: NIL, next: NIL });
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+pub struct List {
+  head: usize,
+  tail: usize,
+}
+
+impl List {
+  pub fn new() -> List {
+    List { head: NIL, tail: NIL }
+  }
+
+  pub fn back(&self) -> Option<usize> {
+    if self.tail == NIL { None } else { Some(self.tail) }
+  }
+
+  pub fn push_front(&mut self, links: &mut Links, slot: usize) {
+    links.ensure(slot);
+    links.links[slot] = Link { prev: NIL, next: self.head };
+    if self.head == NIL {
+      self.tail = slot;
+    } else {
+      links.links[self.head].prev = slot;
+    }
+    self.head = slot;
+  }
+
+  pub fn unlink(&mut self, links: &mut Links, slot: usize) {
+    let Link { prev, next } = links.links[slot];
+    if prev == NIL {
+      self.head = next;
+    } else {
+      links.links[prev].next = next;
+    }
+    if next == NIL {
+      self.tail = prev;
+    } else {
+      links.links[next].prev = prev;
+    }
+    links.links[slot] = Link { prev: NIL, next: NIL };
+  }
+
+  pub fn move_to_front(&mut self, links: &mut Links, slot: usize) {
+    if self.head != slot {
+      self.unlink(links, slot);
+      self.push_front(links, slot);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn push_unlink_move() {
+    let mut links = Links::with_capacity(0);
+    let mut list = List::new();
+    list.push_front(&mut links, 0);
+    list.push_front(&mut links, 1);
+    list.push_front(&mut links, 2);
+    list.move_to_front(&mut links, 0);
+    assert_eq!(links.prev(1), Some(2));
+    assert_eq!(links.prev(0), None);
+    list.unlink(&mut links, 1);
+    assert_eq!(list.back(), Some(2));
+    list.unlink(&mut links, 2);
+    assert_eq!(list.back(), Some(0));
+    list.unlink(&mut links, 0);
+    assert_eq!(list.back(), None);
+  }
+}
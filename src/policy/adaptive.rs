@@ -0,0 +1,285 @@
+use list::{ Links, List };
+use policy::EvictionPolicy;
+use policy::ghost::GhostList;
+
+// Adaptive Replacement Cache (Megiddo and Modha). Entries seen once live in
+// T1 and entries seen again in T2. The keys of entries evicted from each are
+// remembered in the ghost lists B1 and B2, and a miss that hits a ghost list
+// moves the target size `p` of T1 towards whichever list would have kept the
+// entry, so the split between recency and frequency adapts to the workload.
+pub struct Adaptive {
+  capacity: usize,
+  p: usize,
+  links: Links,
+  hashes: Vec<u64>,
+  frequent: Vec<bool>,
+  t1: List,
+  t2: List,
+  t1_len: usize,
+  t2_len: usize,
+  b1: GhostList,
+  b2: GhostList,
+  // Whether the entry being inserted was found in B1 or B2.
+  incoming: Option<Ghost>,
+  evicting: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Ghost {
+  B1,
+  B2,
+}
+
+impl Adaptive {
+  pub fn new() -> Adaptive {
+    Adaptive {
+      capacity: 0,
+      p: 0,
+      links: Links::with_capacity(0),
+      hashes: Vec::new(),
+      frequent: Vec::new(),
+      t1: List::new(),
+      t2: List::new(),
+      t1_len: 0,
+      t2_len: 0,
+      b1: GhostList::new(0),
+      b2: GhostList::new(0),
+      incoming: None,
+      evicting: None,
+    }
+  }
+
+  fn unlink(&mut self, slot: usize) {
+    if self.frequent[slot] {
+      self.t2.unlink(&mut self.links, slot);
+      self.t2_len -= 1;
+    } else {
+      self.t1.unlink(&mut self.links, slot);
+      self.t1_len -= 1;
+    }
+  }
+
+  fn push(&mut self, slot: usize, frequent: bool) {
+    self.frequent[slot] = frequent;
+    if frequent {
+      self.t2.push_front(&mut self.links, slot);
+      self.t2_len += 1;
+    } else {
+      self.t1.push_front(&mut self.links, slot);
+      self.t1_len += 1;
+    }
+  }
+
+  // Keeps T1 and B1 to `capacity` entries between them, counting the entry
+  // being inserted unless it came from a ghost list and so goes into T2, and
+  // the ghost lists to `capacity` entries between them. When T1 alone is full
+  // its evicted keys are not remembered at all.
+  fn remember(&mut self, hash: u64, ghost: Ghost) {
+    let joining_t1 = if self.incoming.is_none() { 1 } else { 0 };
+    match ghost {
+      Ghost::B1 if self.t1_len + self.b1.len() + joining_t1 >= self.capacity => {
+        if self.b1.len() == 0 {
+          return;
+        }
+        self.b1.pop_oldest();
+      },
+      _ if self.b1.len() + self.b2.len() >= self.capacity => self.forget(),
+      _ => (),
+    }
+    match ghost {
+      Ghost::B1 => self.b1.push(hash),
+      Ghost::B2 => self.b2.push(hash),
+    }
+  }
+
+  // Drops the oldest ghost from B2, or from B1 once B2 is empty.
+  fn forget(&mut self) {
+    if self.b2.len() > 0 {
+      self.b2.pop_oldest();
+    } else {
+      self.b1.pop_oldest();
+    }
+  }
+}
+
+impl Default for Adaptive {
+  fn default() -> Adaptive {
+    Adaptive::new()
+  }
+}
+
+impl EvictionPolicy for Adaptive {
+  fn inserting(&mut self, hash: u64) {
+    self.incoming = if self.b1.remove(hash) {
+      let delta = (self.b2.len() / self.b1.len().max(1)).max(1);
+      self.p = (self.p + delta).min(self.capacity);
+      Some(Ghost::B1)
+    } else if self.b2.remove(hash) {
+      let delta = (self.b1.len() / self.b2.len().max(1)).max(1);
+      self.p = self.p.saturating_sub(delta);
+      Some(Ghost::B2)
+    } else {
+      None
+    };
+  }
+
+  fn insert(&mut self, slot: usize, hash: u64) {
+    if slot >= self.hashes.len() {
+      self.hashes.resize(slot + 1, 0);
+      self.frequent.resize(slot + 1, false);
+    }
+    self.hashes[slot] = hash;
+    let frequent = self.incoming.take().is_some();
+    self.push(slot, frequent);
+  }
+
+  fn access(&mut self, slot: usize, _: u64) {
+    self.unlink(slot);
+    self.push(slot, true);
+  }
+
+  fn remove(&mut self, slot: usize) {
+    self.unlink(slot);
+    if self.evicting == Some(slot) {
+      self.evicting = None;
+      let ghost = if self.frequent[slot] { Ghost::B2 } else { Ghost::B1 };
+      self.remember(self.hashes[slot], ghost);
+    }
+  }
+
+  fn victim(&mut self) -> Option<usize> {
+    let from_t1 = self.t1_len > 0
+      && (self.t1_len > self.p || (self.incoming == Some(Ghost::B2) && self.t1_len == self.p));
+    let victim = if from_t1 {
+      self.t1.back()
+    } else {
+      self.t2.back().or_else(|| self.t1.back())
+    };
+    self.evicting = victim;
+    victim
+  }
+
+  fn clear(&mut self) {
+    self.p = 0;
+    self.t1 = List::new();
+    self.t2 = List::new();
+    self.t1_len = 0;
+    self.t2_len = 0;
+    self.b1.clear();
+    self.b2.clear();
+    self.incoming = None;
+    self.evicting = None;
+  }
+
+  // `p` and the ghost lists shrink with the capacity, keeping the newest
+  // ghosts.
+  fn set_capacity(&mut self, capacity: usize) {
+    self.capacity = capacity;
+    self.p = self.p.min(capacity);
+    self.b1.set_capacity(capacity);
+    self.b2.set_capacity(capacity);
+    while self.b1.len() > 0 && self.t1_len + self.b1.len() > capacity {
+      self.b1.pop_oldest();
+    }
+    while self.b1.len() + self.b2.len() > capacity {
+      self.forget();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ghost_hits_adapt() {
+    let mut arc = Adaptive::new();
+    arc.set_capacity(2);
+    arc.inserting(10);
+    arc.insert(0, 10);
+    arc.access(0, 10);
+    arc.inserting(11);
+    arc.insert(1, 11);
+    arc.inserting(12);
+    let victim = arc.victim().unwrap();
+    assert_eq!(victim, 1);
+    arc.remove(victim);
+    arc.insert(1, 12);
+    assert_eq!(arc.b1.len(), 1);
+
+    // Seeing 11 again after its eviction grows the recency target, and it
+    // comes back as a frequent entry.
+    arc.inserting(11);
+    assert_eq!(arc.p, 1);
+    let victim = arc.victim().unwrap();
+    arc.remove(victim);
+    arc.insert(victim, 11);
+    assert!(arc.frequent[victim]);
+  }
+
+  #[test]
+  fn recency_side_bounded() {
+    let mut arc = Adaptive::new();
+    arc.set_capacity(4);
+    for slot in 0..4 {
+      arc.inserting(slot as u64);
+      arc.insert(slot, slot as u64);
+    }
+    // A scan of new keys only ever evicts from T1.
+    for hash in 4..20 {
+      arc.inserting(hash);
+      let victim = arc.victim().unwrap();
+      arc.remove(victim);
+      arc.insert(victim, hash);
+      assert!(arc.t1_len + arc.b1.len() <= 4);
+    }
+    assert_eq!((arc.t1_len, arc.b1.len()), (4, 0));
+
+    // Once some entries are frequent T1 has room to spare for ghosts.
+    arc.access(0, 16);
+    arc.access(1, 17);
+    for hash in 20..30 {
+      arc.inserting(hash);
+      let victim = arc.victim().unwrap();
+      arc.remove(victim);
+      arc.insert(victim, hash);
+      assert!(arc.t1_len + arc.b1.len() <= 4);
+      assert!(arc.b1.len() + arc.b2.len() <= 4);
+    }
+    assert!(arc.b1.len() + arc.b2.len() > 0);
+  }
+
+  #[test]
+  fn set_capacity_shrinks() {
+    use LruCache;
+
+    let cash = LruCache::builder(8).eviction_policy(Adaptive::new()).build();
+    for i in 0..16u32 {
+      cash.insert(i, i);
+    }
+    cash.set_limit(2);
+    assert_eq!(cash.len(), 2);
+    for i in 16..20u32 {
+      cash.insert(i, i);
+    }
+    assert_eq!(cash.len(), 2);
+
+    let mut arc = Adaptive::new();
+    arc.set_capacity(4);
+    for slot in 0..4 {
+      arc.inserting(slot as u64);
+      arc.insert(slot, slot as u64);
+      arc.access(slot, slot as u64);
+    }
+    for hash in 4..8 {
+      arc.inserting(hash);
+      let victim = arc.victim().unwrap();
+      arc.remove(victim);
+      arc.insert(victim, hash);
+    }
+    arc.p = 4;
+    assert_eq!(arc.b1.len() + arc.b2.len(), 4);
+    arc.set_capacity(2);
+    assert_eq!((arc.p, arc.b1.len() + arc.b2.len()), (2, 2));
+  }
+}
@@ -0,0 +1,57 @@
+use list::{ Links, List };
+use policy::EvictionPolicy;
+
+// Evicts the oldest entry, reads do not affect the order.
+pub struct Fifo {
+  links: Links,
+  queue: List,
+}
+
+impl Fifo {
+  pub fn new() -> Fifo {
+    Fifo { links: Links::with_capacity(0), queue: List::new() }
+  }
+}
+
+impl Default for Fifo {
+  fn default() -> Fifo {
+    Fifo::new()
+  }
+}
+
+impl EvictionPolicy for Fifo {
+  fn insert(&mut self, slot: usize, _: u64) {
+    self.queue.push_front(&mut self.links, slot);
+  }
+
+  fn access(&mut self, _: usize, _: u64) {
+  }
+
+  fn remove(&mut self, slot: usize) {
+    self.queue.unlink(&mut self.links, slot);
+  }
+
+  fn victim(&mut self) -> Option<usize> {
+    self.queue.back()
+  }
+
+  fn clear(&mut self) {
+    self.queue = List::new();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ignores_access() {
+    let mut fifo = Fifo::new();
+    fifo.insert(0, 0);
+    fifo.insert(1, 0);
+    fifo.access(0, 0);
+    assert_eq!(fifo.victim(), Some(0));
+    fifo.remove(0);
+    assert_eq!(fifo.victim(), Some(1));
+  }
+}
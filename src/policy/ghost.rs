@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use list::{ Links, List };
+
+// Remembers the key hashes of recently evicted entries, oldest first out
+// once `capacity` is reached.
+pub struct GhostList {
+  capacity: usize,
+  index: HashMap<u64, usize>,
+  hashes: Vec<u64>,
+  free: Vec<usize>,
+  links: Links,
+  list: List,
+}
+
+impl GhostList {
+  pub fn new(capacity: usize) -> GhostList {
+    GhostList {
+      capacity,
+      index: HashMap::new(),
+      hashes: Vec::new(),
+      free: Vec::new(),
+      links: Links::with_capacity(0),
+      list: List::new(),
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.index.len()
+  }
+
+  pub fn push(&mut self, hash: u64) {
+    if self.capacity == 0 {
+      return;
+    }
+    self.remove(hash);
+    if self.len() >= self.capacity {
+      self.pop_oldest();
+    }
+    let node = match self.free.pop() {
+      Some(node) => {
+        self.hashes[node] = hash;
+        node
+      },
+      None => {
+        self.hashes.push(hash);
+        self.hashes.len() - 1
+      },
+    };
+    self.list.push_front(&mut self.links, node);
+    self.index.insert(hash, node);
+  }
+
+  pub fn remove(&mut self, hash: u64) -> bool {
+    match self.index.remove(&hash) {
+      Some(node) => {
+        self.list.unlink(&mut self.links, node);
+        self.free.push(node);
+        true
+      },
+      None => false,
+    }
+  }
+
+  pub fn pop_oldest(&mut self) {
+    if let Some(node) = self.list.back() {
+      let hash = self.hashes[node];
+      self.remove(hash);
+    }
+  }
+
+  // Forgets the oldest hashes over the new capacity.
+  pub fn set_capacity(&mut self, capacity: usize) {
+    self.capacity = capacity;
+    while self.len() > capacity {
+      self.pop_oldest();
+    }
+  }
+
+  pub fn clear(&mut self) {
+    self.index.clear();
+    self.hashes.clear();
+    self.free.clear();
+    self.list = List::new();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bounded() {
+    let mut ghosts = GhostList::new(2);
+    ghosts.push(1);
+    ghosts.push(2);
+    ghosts.push(1);
+    ghosts.push(3);
+    assert!(!ghosts.remove(2));
+    assert!(ghosts.remove(3));
+    assert!(!ghosts.remove(3));
+    assert_eq!(ghosts.len(), 1);
+    assert!(ghosts.remove(1));
+
+    ghosts.push(4);
+    ghosts.push(5);
+    ghosts.set_capacity(1);
+    assert_eq!(ghosts.len(), 1);
+    assert!(ghosts.remove(5));
+  }
+}
@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+
+use list::{ Links, List };
+use policy::EvictionPolicy;
+
+// Evicts the least frequently used entry, breaking ties by evicting the least
+// recently used of them. Counts start at 1 when an entry is inserted and are
+// forgotten when it leaves the cache.
+pub struct Lfu {
+  links: Links,
+  counts: Vec<u32>,
+  // Only holds non-empty lists, so the first is the one to evict from.
+  buckets: BTreeMap<u32, List>,
+}
+
+impl Lfu {
+  pub fn new() -> Lfu {
+    Lfu { links: Links::with_capacity(0), counts: Vec::new(), buckets: BTreeMap::new() }
+  }
+
+  fn push(&mut self, slot: usize, count: u32) {
+    if slot >= self.counts.len() {
+      self.counts.resize(slot + 1, 0);
+    }
+    self.counts[slot] = count;
+    self.buckets.entry(count).or_insert_with(List::new).push_front(&mut self.links, slot);
+  }
+
+  fn unlink(&mut self, slot: usize) -> u32 {
+    let count = self.counts[slot];
+    let empty = {
+      let bucket = self.buckets.get_mut(&count).unwrap();
+      bucket.unlink(&mut self.links, slot);
+      bucket.back().is_none()
+    };
+    if empty {
+      self.buckets.remove(&count);
+    }
+    count
+  }
+}
+
+impl Default for Lfu {
+  fn default() -> Lfu {
+    Lfu::new()
+  }
+}
+
+impl EvictionPolicy for Lfu {
+  fn insert(&mut self, slot: usize, _: u64) {
+    self.push(slot, 1);
+  }
+
+  fn access(&mut self, slot: usize, _: u64) {
+    let count = self.unlink(slot);
+    self.push(slot, count.saturating_add(1));
+  }
+
+  fn remove(&mut self, slot: usize) {
+    self.unlink(slot);
+  }
+
+  fn victim(&mut self) -> Option<usize> {
+    self.buckets.values().next().and_then(List::back)
+  }
+
+  fn clear(&mut self) {
+    self.buckets.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn evicts_least_frequent() {
+    let mut lfu = Lfu::new();
+    lfu.insert(0, 0);
+    lfu.insert(1, 0);
+    lfu.insert(2, 0);
+    lfu.access(0, 0);
+    lfu.access(0, 0);
+    lfu.access(2, 0);
+    assert_eq!(lfu.victim(), Some(1));
+    lfu.remove(1);
+    assert_eq!(lfu.victim(), Some(2));
+    lfu.access(2, 0);
+    lfu.access(2, 0);
+    assert_eq!(lfu.victim(), Some(0));
+  }
+}
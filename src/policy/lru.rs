@@ -0,0 +1,53 @@
+use list::{ Links, List };
+use policy::EvictionPolicy;
+
+// Evicts the least recently used entry.
+pub struct Lru {
+  links: Links,
+  recency: List,
+}
+
+impl Lru {
+  pub fn new() -> Lru {
+    Lru { links: Links::with_capacity(0), recency: List::new() }
+  }
+}
+
+impl Default for Lru {
+  fn default() -> Lru {
+    Lru::new()
+  }
+}
+
+impl EvictionPolicy for Lru {
+  fn insert(&mut self, slot: usize, _: u64) {
+    self.recency.push_front(&mut self.links, slot);
+  }
+
+  fn access(&mut self, slot: usize, _: u64) {
+    self.recency.move_to_front(&mut self.links, slot);
+  }
+
+  fn remove(&mut self, slot: usize) {
+    self.recency.unlink(&mut self.links, slot);
+  }
+
+  fn victim(&mut self) -> Option<usize> {
+    self.recency.back()
+  }
+
+  fn clear(&mut self) {
+    self.recency = List::new();
+  }
+
+  fn recency(&self) -> Option<Vec<usize>> {
+    let mut slots = Vec::new();
+    let mut slot = self.recency.back();
+    while let Some(next) = slot {
+      slots.push(next);
+      slot = self.links.prev(next);
+    }
+    slots.reverse();
+    Some(slots)
+  }
+}
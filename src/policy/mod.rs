@@ -0,0 +1,60 @@
+// Eviction policies decide which entry leaves the cache when it is over its
+// limit. The cache stores each entry in a numbered slot and tells the policy
+// about every slot that is filled, read and emptied; slots are reused after
+// being emptied and stay below the cache's peak entry count.
+
+mod adaptive;
+mod fifo;
+mod ghost;
+mod lfu;
+mod lru;
+mod random;
+mod sieve;
+mod tiny_lfu;
+mod two_queue;
+
+pub use self::adaptive::Adaptive;
+pub use self::fifo::Fifo;
+pub use self::lfu::Lfu;
+pub use self::lru::Lru;
+pub use self::random::Random;
+pub use self::sieve::Sieve;
+pub use self::tiny_lfu::TinyLfu;
+pub use self::two_queue::TwoQueue;
+
+pub trait EvictionPolicy: Send + Sync {
+  // An entry with this key hash is about to be inserted, called before any
+  // victims are chosen to make room for it.
+  fn inserting(&mut self, _hash: u64) {
+  }
+
+  // A new entry was stored in `slot`. `hash` is the hash of its key.
+  fn insert(&mut self, slot: usize, hash: u64);
+
+  // The entry in `slot` was read by a `get` or a loader hit.
+  fn access(&mut self, slot: usize, hash: u64);
+
+  // The entry in `slot` left the cache, whether it was chosen as a victim or
+  // removed for any other reason.
+  fn remove(&mut self, slot: usize);
+
+  // The entry to evict to make room for another one. It is removed straight
+  // away, so the policy will see a matching `remove`.
+  fn victim(&mut self) -> Option<usize>;
+
+  fn clear(&mut self);
+
+  // The cache's entry limit, called when the cache is built and whenever
+  // `set_limit` changes it, before any entries are evicted to fit. Policies
+  // that size their own structures from the limit do so here, so none of
+  // them take a capacity when constructed.
+  fn set_capacity(&mut self, _capacity: usize) {
+  }
+
+  // The tracked slots, most recently used first, for listing the cache's
+  // entries. Policies that do not keep a recency order return `None` and the
+  // cache orders entries by their last access time instead.
+  fn recency(&self) -> Option<Vec<usize>> {
+    None
+  }
+}
@@ -0,0 +1,90 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+use policy::EvictionPolicy;
+
+// Evicts an entry chosen uniformly at random.
+pub struct Random {
+  slots: Vec<usize>,
+  // Where each slot is in `slots`.
+  positions: Vec<usize>,
+  state: u64,
+}
+
+impl Random {
+  pub fn new() -> Random {
+    Random::with_seed(RandomState::new().hash_one(0u8))
+  }
+
+  // For reproducible eviction order.
+  pub fn with_seed(seed: u64) -> Random {
+    Random { slots: Vec::new(), positions: Vec::new(), state: seed | 1 }
+  }
+
+  // xorshift64*
+  fn next(&mut self) -> u64 {
+    self.state ^= self.state >> 12;
+    self.state ^= self.state << 25;
+    self.state ^= self.state >> 27;
+    self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+  }
+}
+
+impl Default for Random {
+  fn default() -> Random {
+    Random::new()
+  }
+}
+
+impl EvictionPolicy for Random {
+  fn insert(&mut self, slot: usize, _: u64) {
+    if slot >= self.positions.len() {
+      self.positions.resize(slot + 1, 0);
+    }
+    self.positions[slot] = self.slots.len();
+    self.slots.push(slot);
+  }
+
+  fn access(&mut self, _: usize, _: u64) {
+  }
+
+  fn remove(&mut self, slot: usize) {
+    let position = self.positions[slot];
+    self.slots.swap_remove(position);
+    if let Some(&moved) = self.slots.get(position) {
+      self.positions[moved] = position;
+    }
+  }
+
+  fn victim(&mut self) -> Option<usize> {
+    if self.slots.is_empty() {
+      None
+    } else {
+      let index = self.next() % self.slots.len() as u64;
+      Some(self.slots[index as usize])
+    }
+  }
+
+  fn clear(&mut self) {
+    self.slots.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tracks_slots() {
+    let mut random = Random::with_seed(7);
+    for slot in 0..10 {
+      random.insert(slot, 0);
+    }
+    for _ in 0..10 {
+      let victim = random.victim().unwrap();
+      random.remove(victim);
+      assert!(!random.slots.contains(&victim));
+    }
+    assert_eq!(random.victim(), None);
+  }
+}
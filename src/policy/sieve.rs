@@ -0,0 +1,84 @@
+use list::{ Links, List };
+use policy::EvictionPolicy;
+
+// SIEVE. Entries are kept in insertion order and a read only sets a visited
+// bit. A hand walks from the oldest entry towards the newest, clearing the
+// bits it passes, and evicts the first entry that was not visited since the
+// hand last went by. Once-read entries leave quickly, without ever reordering
+// the queue on a hit.
+pub struct Sieve {
+  links: Links,
+  queue: List,
+  visited: Vec<bool>,
+  hand: Option<usize>,
+}
+
+impl Sieve {
+  pub fn new() -> Sieve {
+    Sieve { links: Links::with_capacity(0), queue: List::new(), visited: Vec::new(), hand: None }
+  }
+}
+
+impl Default for Sieve {
+  fn default() -> Sieve {
+    Sieve::new()
+  }
+}
+
+impl EvictionPolicy for Sieve {
+  fn insert(&mut self, slot: usize, _: u64) {
+    if slot >= self.visited.len() {
+      self.visited.resize(slot + 1, false);
+    }
+    self.visited[slot] = false;
+    self.queue.push_front(&mut self.links, slot);
+  }
+
+  fn access(&mut self, slot: usize, _: u64) {
+    self.visited[slot] = true;
+  }
+
+  fn remove(&mut self, slot: usize) {
+    if self.hand == Some(slot) {
+      self.hand = self.links.prev(slot);
+    }
+    self.queue.unlink(&mut self.links, slot);
+  }
+
+  fn victim(&mut self) -> Option<usize> {
+    let mut hand = self.hand.or_else(|| self.queue.back())?;
+    while self.visited[hand] {
+      self.visited[hand] = false;
+      hand = self.links.prev(hand).or_else(|| self.queue.back()).unwrap();
+    }
+    self.hand = Some(hand);
+    Some(hand)
+  }
+
+  fn clear(&mut self) {
+    self.queue = List::new();
+    self.hand = None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn skips_visited() {
+    let mut sieve = Sieve::new();
+    for slot in 0..4 {
+      sieve.insert(slot, 0);
+    }
+    sieve.access(0, 0);
+    sieve.access(2, 0);
+    assert_eq!(sieve.victim(), Some(1));
+    sieve.remove(1);
+    // The hand carries on from where it stopped.
+    assert_eq!(sieve.victim(), Some(3));
+    sieve.remove(3);
+    sieve.insert(1, 0);
+    assert_eq!(sieve.victim(), Some(0));
+  }
+}
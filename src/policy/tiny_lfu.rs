@@ -0,0 +1,313 @@
+use list::{ Links, List };
+use policy::EvictionPolicy;
+
+// Window TinyLFU. New entries go into a small LRU window. When the window
+// overflows its oldest entry becomes a candidate for the main area, and is
+// only admitted if its key has been seen more often than the main area's
+// victim, so a scan of keys that are read once cannot flush out hot entries.
+//
+// The main area is a segmented LRU: entries start in probation and move to
+// protected when read again.
+pub struct TinyLfu {
+  sketch: FrequencySketch,
+  links: Links,
+  hashes: Vec<u64>,
+  segments: Vec<Segment>,
+  window: List,
+  probation: List,
+  protected: List,
+  window_len: usize,
+  protected_len: usize,
+  window_capacity: usize,
+  protected_capacity: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Segment {
+  Window,
+  Probation,
+  Protected,
+}
+
+impl TinyLfu {
+  pub fn new() -> TinyLfu {
+    TinyLfu {
+      sketch: FrequencySketch::new(0),
+      links: Links::with_capacity(0),
+      hashes: Vec::new(),
+      segments: Vec::new(),
+      window: List::new(),
+      probation: List::new(),
+      protected: List::new(),
+      window_len: 0,
+      protected_len: 0,
+      window_capacity: 1,
+      protected_capacity: 0,
+    }
+  }
+
+  fn list(&mut self, segment: Segment) -> (&mut List, &mut Links) {
+    let list = match segment {
+      Segment::Window => &mut self.window,
+      Segment::Probation => &mut self.probation,
+      Segment::Protected => &mut self.protected,
+    };
+    (list, &mut self.links)
+  }
+
+  fn unlink(&mut self, slot: usize) {
+    let segment = self.segments[slot];
+    {
+      let (list, links) = self.list(segment);
+      list.unlink(links, slot);
+    }
+    match segment {
+      Segment::Window => self.window_len -= 1,
+      Segment::Protected => self.protected_len -= 1,
+      Segment::Probation => (),
+    }
+  }
+
+  fn push(&mut self, slot: usize, segment: Segment) {
+    self.segments[slot] = segment;
+    {
+      let (list, links) = self.list(segment);
+      list.push_front(links, slot);
+    }
+    match segment {
+      Segment::Window => self.window_len += 1,
+      Segment::Protected => self.protected_len += 1,
+      Segment::Probation => (),
+    }
+  }
+
+  fn move_to(&mut self, slot: usize, segment: Segment) {
+    self.unlink(slot);
+    self.push(slot, segment);
+  }
+
+  fn frequency(&self, slot: usize) -> u8 {
+    self.sketch.frequency(self.hashes[slot])
+  }
+}
+
+impl Default for TinyLfu {
+  fn default() -> TinyLfu {
+    TinyLfu::new()
+  }
+}
+
+impl EvictionPolicy for TinyLfu {
+  fn insert(&mut self, slot: usize, hash: u64) {
+    if slot >= self.hashes.len() {
+      self.hashes.resize(slot + 1, 0);
+      self.segments.resize(slot + 1, Segment::Window);
+    }
+    self.hashes[slot] = hash;
+    self.sketch.increment(hash);
+    self.push(slot, Segment::Window);
+    // Only reached while the cache is filling up, once it is full `victim`
+    // has already made room in the window.
+    while self.window_len > self.window_capacity {
+      let oldest = self.window.back().unwrap();
+      self.move_to(oldest, Segment::Probation);
+    }
+  }
+
+  fn access(&mut self, slot: usize, hash: u64) {
+    self.sketch.increment(hash);
+    match self.segments[slot] {
+      Segment::Window => self.move_to(slot, Segment::Window),
+      Segment::Protected => self.move_to(slot, Segment::Protected),
+      Segment::Probation => {
+        self.move_to(slot, Segment::Protected);
+        if self.protected_len > self.protected_capacity {
+          let demoted = self.protected.back().unwrap();
+          self.move_to(demoted, Segment::Probation);
+        }
+      },
+    }
+  }
+
+  fn remove(&mut self, slot: usize) {
+    self.unlink(slot);
+  }
+
+  fn victim(&mut self) -> Option<usize> {
+    // The entry about to be inserted will push the oldest window entry out,
+    // it has to win against the main area's victim to be admitted.
+    let candidate = if self.window_len >= self.window_capacity {
+      let oldest = self.window.back();
+      if let Some(oldest) = oldest {
+        self.move_to(oldest, Segment::Probation);
+      }
+      oldest
+    } else {
+      None
+    };
+
+    let victim = self.probation.back()
+      .filter(|&victim| Some(victim) != candidate)
+      .or_else(|| self.protected.back());
+
+    match (candidate, victim) {
+      (Some(candidate), Some(victim)) => {
+        if self.frequency(candidate) > self.frequency(victim) {
+          Some(victim)
+        } else {
+          Some(candidate)
+        }
+      },
+      (candidate, victim) => victim.or(candidate).or_else(|| self.window.back()),
+    }
+  }
+
+  fn clear(&mut self) {
+    self.window = List::new();
+    self.probation = List::new();
+    self.protected = List::new();
+    self.window_len = 0;
+    self.protected_len = 0;
+  }
+
+  // The window gets 1% of the capacity and the protected segment 80% of the
+  // rest. Entries over the new sizes move down to probation. The frequency
+  // sketch starts again if it needs a different width.
+  fn set_capacity(&mut self, capacity: usize) {
+    self.window_capacity = (capacity / 100).max(1);
+    self.protected_capacity = capacity.saturating_sub(self.window_capacity) * 4 / 5;
+    while self.window_len > self.window_capacity {
+      let oldest = self.window.back().unwrap();
+      self.move_to(oldest, Segment::Probation);
+    }
+    while self.protected_len > self.protected_capacity {
+      let oldest = self.protected.back().unwrap();
+      self.move_to(oldest, Segment::Probation);
+    }
+    if FrequencySketch::width(capacity) != self.sketch.mask + 1 {
+      self.sketch = FrequencySketch::new(capacity);
+    }
+  }
+}
+
+// A count-min sketch of 4 bit counters estimating how often each key hash has
+// been seen. Every counter is halved after a sample of increments, so old
+// popularity fades.
+struct FrequencySketch {
+  table: Vec<u8>,
+  mask: usize,
+  additions: usize,
+  sample_size: usize,
+}
+
+const DEPTH: usize = 4;
+const MAX_COUNT: u8 = 15;
+const SEEDS: [u64; DEPTH] = [
+  0xc3a5_c85c_97cb_3127,
+  0xb492_b66f_be98_f273,
+  0x9ae1_6a3b_2f90_404f,
+  0xcbf2_9ce4_8422_2325,
+];
+
+impl FrequencySketch {
+  fn width(capacity: usize) -> usize {
+    capacity.clamp(16, 1 << 24).next_power_of_two()
+  }
+
+  fn new(capacity: usize) -> FrequencySketch {
+    let width = FrequencySketch::width(capacity);
+    FrequencySketch {
+      table: vec![0; width * DEPTH],
+      mask: width - 1,
+      additions: 0,
+      sample_size: width * 10,
+    }
+  }
+
+  fn index(&self, hash: u64, row: usize) -> usize {
+    let mixed = hash.wrapping_add(SEEDS[row]).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    row * (self.mask + 1) + ((mixed >> 32) as usize & self.mask)
+  }
+
+  fn frequency(&self, hash: u64) -> u8 {
+    (0..DEPTH).map(|row| self.table[self.index(hash, row)]).min().unwrap()
+  }
+
+  fn increment(&mut self, hash: u64) {
+    let mut added = false;
+    for row in 0..DEPTH {
+      let index = self.index(hash, row);
+      if self.table[index] < MAX_COUNT {
+        self.table[index] += 1;
+        added = true;
+      }
+    }
+    if added {
+      self.additions += 1;
+      if self.additions >= self.sample_size {
+        for count in &mut self.table {
+          *count /= 2;
+        }
+        self.additions /= 2;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use LruCache;
+
+  #[test]
+  fn sketch_counts_and_ages() {
+    let mut sketch = FrequencySketch::new(16);
+    for _ in 0..5 {
+      sketch.increment(42);
+    }
+    assert_eq!(sketch.frequency(42), 5);
+    for _ in 0..20 {
+      sketch.increment(42);
+    }
+    assert_eq!(sketch.frequency(42), MAX_COUNT);
+    for hash in 0..200 {
+      sketch.increment(hash * 7919);
+    }
+    assert!(sketch.frequency(42) < MAX_COUNT);
+  }
+
+  #[test]
+  fn scan_resistant() {
+    let cash = LruCache::builder(100).eviction_policy(TinyLfu::new()).build();
+    for _ in 0..4 {
+      for i in 0..50u32 {
+        cash.get_or_insert_with(i, || i);
+      }
+    }
+    for i in 1000..3000u32 {
+      cash.get_or_insert_with(i, || i);
+    }
+    let hot = (0..50u32).filter(|i| cash.contains_key(i)).count();
+    assert!(hot >= 45, "only {} hot entries survived the scan", hot);
+    assert_eq!(cash.len(), 100);
+  }
+
+  #[test]
+  fn set_capacity_resizes() {
+    let mut policy = TinyLfu::new();
+    policy.set_capacity(1000);
+    for slot in 0..1000 {
+      policy.insert(slot, slot as u64);
+    }
+    for slot in 0..1000 {
+      policy.access(slot, slot as u64);
+    }
+    assert_eq!((policy.window_capacity, policy.protected_capacity), (10, 792));
+    assert_eq!(policy.protected_len, 792);
+
+    policy.set_capacity(100);
+    assert_eq!((policy.window_capacity, policy.protected_capacity), (1, 79));
+    assert_eq!((policy.window_len, policy.protected_len), (1, 79));
+    assert_eq!(policy.sketch.mask + 1, 128);
+  }
+}
@@ -0,0 +1,164 @@
+use list::{ Links, List };
+use policy::EvictionPolicy;
+use policy::ghost::GhostList;
+
+// 2Q (Johnson and Shasha). New entries go into the FIFO A1in, and the keys
+// of entries evicted from it are remembered in the ghost list A1out. Only a
+// key that comes back while still in A1out is inserted into the main queue
+// Am, so entries read during a scan never reach it.
+//
+// Am is a CLOCK queue: a hit sets a referenced bit instead of moving the
+// entry, and a referenced entry gets a second chance when it is the victim.
+pub struct TwoQueue {
+  in_capacity: usize,
+  links: Links,
+  hashes: Vec<u64>,
+  main: Vec<bool>,
+  referenced: Vec<bool>,
+  a1in: List,
+  am: List,
+  a1in_len: usize,
+  a1out: GhostList,
+  evicting: Option<usize>,
+}
+
+impl TwoQueue {
+  pub fn new() -> TwoQueue {
+    TwoQueue {
+      in_capacity: 1,
+      links: Links::with_capacity(0),
+      hashes: Vec::new(),
+      main: Vec::new(),
+      referenced: Vec::new(),
+      a1in: List::new(),
+      am: List::new(),
+      a1in_len: 0,
+      a1out: GhostList::new(1),
+      evicting: None,
+    }
+  }
+}
+
+impl Default for TwoQueue {
+  fn default() -> TwoQueue {
+    TwoQueue::new()
+  }
+}
+
+impl EvictionPolicy for TwoQueue {
+  fn insert(&mut self, slot: usize, hash: u64) {
+    if slot >= self.hashes.len() {
+      self.hashes.resize(slot + 1, 0);
+      self.main.resize(slot + 1, false);
+      self.referenced.resize(slot + 1, false);
+    }
+    self.hashes[slot] = hash;
+    self.referenced[slot] = false;
+    self.main[slot] = self.a1out.remove(hash);
+    if self.main[slot] {
+      self.am.push_front(&mut self.links, slot);
+    } else {
+      self.a1in.push_front(&mut self.links, slot);
+      self.a1in_len += 1;
+    }
+  }
+
+  fn access(&mut self, slot: usize, _: u64) {
+    if self.main[slot] {
+      self.referenced[slot] = true;
+    }
+  }
+
+  fn remove(&mut self, slot: usize) {
+    if self.main[slot] {
+      self.am.unlink(&mut self.links, slot);
+    } else {
+      self.a1in.unlink(&mut self.links, slot);
+      self.a1in_len -= 1;
+      if self.evicting == Some(slot) {
+        self.a1out.push(self.hashes[slot]);
+      }
+    }
+    if self.evicting == Some(slot) {
+      self.evicting = None;
+    }
+  }
+
+  fn victim(&mut self) -> Option<usize> {
+    let victim = if self.a1in_len > self.in_capacity || self.am.back().is_none() {
+      self.a1in.back()
+    } else {
+      let mut victim = self.am.back().unwrap();
+      while self.referenced[victim] {
+        self.referenced[victim] = false;
+        self.am.move_to_front(&mut self.links, victim);
+        victim = self.am.back().unwrap();
+      }
+      Some(victim)
+    };
+    self.evicting = victim;
+    victim
+  }
+
+  fn clear(&mut self) {
+    self.a1in = List::new();
+    self.am = List::new();
+    self.a1in_len = 0;
+    self.a1out.clear();
+    self.evicting = None;
+  }
+
+  // A1in gets a quarter of the capacity and A1out remembers half as many
+  // keys. A1in over its new size is trimmed by the evictions that follow.
+  fn set_capacity(&mut self, capacity: usize) {
+    self.in_capacity = (capacity / 4).max(1);
+    self.a1out.set_capacity((capacity / 2).max(1));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use LruCache;
+  use policy::TwoQueue;
+
+  #[test]
+  fn scan_skips_main_queue() {
+    let cash = LruCache::builder(8).eviction_policy(TwoQueue::new()).build();
+    // Inserted, pushed out of A1in and seen again, so they land in Am.
+    for i in 0..4u32 {
+      cash.insert(i, i);
+    }
+    for i in 10..16u32 {
+      cash.insert(i, i);
+    }
+    for i in 0..4u32 {
+      cash.insert(i, i);
+      cash.get(&i);
+    }
+    for i in 100..200u32 {
+      cash.insert(i, i);
+    }
+    for i in 0..4u32 {
+      assert_eq!(cash.get(&i).map(|a| *a), Some(i));
+    }
+  }
+
+  #[test]
+  fn set_capacity_resizes() {
+    use policy::EvictionPolicy;
+
+    let mut policy = TwoQueue::new();
+    policy.set_capacity(40);
+    assert_eq!(policy.in_capacity, 10);
+    for slot in 0..20 {
+      policy.insert(slot, slot as u64);
+    }
+    for _ in 0..10 {
+      let victim = policy.victim().unwrap();
+      policy.remove(victim);
+    }
+    assert_eq!(policy.a1out.len(), 10);
+    policy.set_capacity(8);
+    assert_eq!((policy.in_capacity, policy.a1out.len()), (2, 4));
+  }
+}
@@ -0,0 +1,100 @@
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::thread;
+
+// Hits on the read path are only recorded here, while holding the cache's
+// shared lock, and handed to the eviction policy the next time someone takes
+// the exclusive lock. Each thread writes to one of several stripes so readers
+// rarely touch the same cache line. A full stripe drops the access rather
+// than blocking, which only makes the policy's view of recency approximate.
+//
+// Because recording happens under the shared lock and draining under the
+// exclusive one, a drained slot always still holds the entry that was read.
+pub struct ReadBuffers {
+  stripes: Box<[Stripe]>,
+}
+
+const STRIPE_SLOTS: usize = 16;
+const MAX_STRIPES: usize = 64;
+
+#[repr(align(64))]
+struct Stripe {
+  // Accesses ever recorded and ever drained, the difference is the number
+  // waiting in `cells`.
+  head: AtomicUsize,
+  tail: AtomicUsize,
+  cells: [AtomicUsize; STRIPE_SLOTS],
+}
+
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+  static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed);
+}
+
+impl ReadBuffers {
+  pub fn new() -> ReadBuffers {
+    let stripes = thread::available_parallelism().map_or(1, |n| n.get()).next_power_of_two().min(MAX_STRIPES);
+    ReadBuffers {
+      stripes: (0..stripes).map(|_| Stripe {
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        cells: Default::default(),
+      }).collect(),
+    }
+  }
+
+  // Must be called with the cache's shared lock held. Returns whether the
+  // stripe is filling up and should be drained.
+  pub fn record(&self, slot: usize) -> bool {
+    let stripe = &self.stripes[STRIPE.with(|stripe| *stripe) & (self.stripes.len() - 1)];
+    let tail = stripe.tail.load(Ordering::Acquire);
+    let mut head = stripe.head.load(Ordering::Relaxed);
+    loop {
+      let len = head.wrapping_sub(tail);
+      if len >= STRIPE_SLOTS {
+        return true;
+      }
+      match stripe.head.compare_exchange_weak(head, head.wrapping_add(1), Ordering::AcqRel, Ordering::Relaxed) {
+        Ok(_) => {
+          stripe.cells[head % STRIPE_SLOTS].store(slot, Ordering::Release);
+          return len + 1 >= STRIPE_SLOTS / 2;
+        },
+        Err(current) => head = current,
+      }
+    }
+  }
+
+  // Must be called with the cache's exclusive lock held, so nothing is being
+  // recorded concurrently.
+  pub fn drain<F: FnMut(usize)>(&self, mut f: F) {
+    for stripe in self.stripes.iter() {
+      let head = stripe.head.load(Ordering::Acquire);
+      let mut tail = stripe.tail.load(Ordering::Relaxed);
+      while tail != head {
+        f(stripe.cells[tail % STRIPE_SLOTS].load(Ordering::Acquire));
+        tail = tail.wrapping_add(1);
+      }
+      stripe.tail.store(head, Ordering::Release);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn records_in_order_and_drops_when_full() {
+    let buffers = ReadBuffers::new();
+    let mut drain = false;
+    for slot in 0..STRIPE_SLOTS + 4 {
+      drain |= buffers.record(slot);
+    }
+    assert!(drain);
+    let mut drained = Vec::new();
+    buffers.drain(|slot| drained.push(slot));
+    assert_eq!(drained, (0..STRIPE_SLOTS).collect::<Vec<_>>());
+    buffers.drain(|_| panic!("already drained"));
+    assert!(!buffers.record(7));
+  }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::ops::Deref;
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::{ mpsc, Arc, Mutex };
+use std::thread;
+use std::time::Duration;
+
+use { nanos, LruCache };
+
+type Reloader<K, V, E> = dyn Fn(&K, Option<&Arc<V>>) -> Result<V, E> + Send + Sync;
+
+// Runs a refresh somewhere other than the caller's thread.
+type Spawn = dyn Fn(Box<dyn FnOnce() + Send>) + Send + Sync;
+
+// An `LruCache` that reloads entries in the background once they are older
+// than a refresh interval. Until the reload finishes `get` keeps returning
+// the stale value, so only a miss ever waits for the reloader. The rest of
+// the `LruCache` API is available through `Deref`.
+//
+// A failed reload keeps the stale value, and the next `get` tries again.
+// Combine with `expire_after_write` to bound how stale a value can get if
+// reloads keep failing or the key stops being read.
+pub struct RefreshingCache<K, V: Send, E> {
+  cache: Arc<LruCache<K, V>>,
+  refresh_after: u64,
+  reloader: Arc<Reloader<K, V, E>>,
+  refreshing: Arc<Mutex<HashSet<K>>>,
+  spawn: Box<Spawn>,
+}
+
+impl<K, V, E> RefreshingCache<K, V, E>
+    where K: Clone + Hash + Eq + Send + Sync + 'static, V: Send + Sync + 'static, E: 'static {
+  // `reloader` is given the current value when refreshing and `None` when
+  // loading a miss. Refreshes run one at a time on a thread owned by the
+  // cache, which exits once the cache is dropped.
+  pub fn new<F>(cache: LruCache<K, V>, refresh_after: Duration, reloader: F) -> RefreshingCache<K, V, E>
+      where F: Fn(&K, Option<&Arc<V>>) -> Result<V, E> + Send + Sync + 'static {
+    let (jobs, queue) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+    thread::spawn(move || {
+      for job in queue {
+        // A panicking reloader only loses its own refresh.
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+      }
+    });
+    let jobs = Mutex::new(jobs);
+    RefreshingCache::with_spawner(cache, refresh_after, reloader, move |job| {
+      let _ = jobs.lock().unwrap().send(job);
+    })
+  }
+
+  // Like `new`, but each refresh is passed to `spawn` to run, for example on
+  // an existing thread pool. At most one refresh per key is outstanding.
+  pub fn with_spawner<F, S>(cache: LruCache<K, V>, refresh_after: Duration, reloader: F, spawn: S) -> RefreshingCache<K, V, E>
+      where F: Fn(&K, Option<&Arc<V>>) -> Result<V, E> + Send + Sync + 'static,
+            S: Fn(Box<dyn FnOnce() + Send>) + Send + Sync + 'static {
+    RefreshingCache {
+      cache: Arc::new(cache),
+      refresh_after: nanos(refresh_after),
+      reloader: Arc::new(reloader),
+      refreshing: Arc::new(Mutex::new(HashSet::new())),
+      spawn: Box::new(spawn),
+    }
+  }
+
+  // Loads a miss like `try_get_or_insert_with`. A hit older than the refresh
+  // interval starts a reload in the background, unless one is already
+  // outstanding for `k`, and is returned straight away.
+  pub fn get(&self, k: &K) -> Result<Arc<V>, E> {
+    let arc = match self.cache.get(k) {
+      Some(arc) => arc,
+      None => return self.cache.try_get_or_insert_with(k.clone(), || (self.reloader)(k, None)),
+    };
+    if self.cache.age(k).is_some_and(|age| age >= self.refresh_after)
+        && self.refreshing.lock().unwrap().insert(k.clone()) {
+      let refresh = Refresh {
+        cache: self.cache.clone(),
+        reloader: self.reloader.clone(),
+        refreshing: self.refreshing.clone(),
+        key: k.clone(),
+        stale: arc.clone(),
+      };
+      (self.spawn)(Box::new(move || refresh.run()));
+    }
+    Ok(arc)
+  }
+}
+
+impl<K, V: Send, E> Deref for RefreshingCache<K, V, E> {
+  type Target = LruCache<K, V>;
+
+  fn deref(&self) -> &LruCache<K, V> {
+    &self.cache
+  }
+}
+
+// A background reload of one key. Dropping it, whether it ran, failed,
+// panicked or was never run, lets the key be refreshed again.
+struct Refresh<K: Hash + Eq, V: Send, E> {
+  cache: Arc<LruCache<K, V>>,
+  reloader: Arc<Reloader<K, V, E>>,
+  refreshing: Arc<Mutex<HashSet<K>>>,
+  key: K,
+  stale: Arc<V>,
+}
+
+impl<K: Clone + Hash + Eq, V: Send, E> Refresh<K, V, E> {
+  // Only swaps the new value in if the stale one is still cached, so a
+  // value inserted or removed meanwhile is not overwritten.
+  fn run(self) {
+    if let Ok(v) = (self.reloader)(&self.key, Some(&self.stale)) {
+      self.cache.replace_if(self.key.clone(), &self.stale, v);
+    }
+  }
+}
+
+impl<K: Hash + Eq, V: Send, E> Drop for Refresh<K, V, E> {
+  fn drop(&mut self) {
+    if let Ok(mut refreshing) = self.refreshing.lock() {
+      refreshing.remove(&self.key);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{ AtomicU32, Ordering };
+  use std::time::Instant;
+
+  use MockClock;
+  use super::*;
+
+  #[test]
+  fn serves_stale_while_reloading() {
+    let clock = MockClock::new();
+    let loads = Arc::new(AtomicU32::new(0));
+    let cash = {
+      let loads = loads.clone();
+      RefreshingCache::new(
+        LruCache::builder(4).clock(clock.clone()).build(),
+        Duration::from_secs(10),
+        move |_: &u8, _: Option<&Arc<u32>>| Ok::<_, ()>(loads.fetch_add(1, Ordering::SeqCst) + 1))
+    };
+    assert_eq!(cash.get(&0).map(|a| *a), Ok(1));
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(cash.get(&0).map(|a| *a), Ok(1));
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(cash.get(&0).map(|a| *a), Ok(1));
+    let start = Instant::now();
+    while cash.peek(&0).map(|a| *a) != Some(2) {
+      assert!(start.elapsed() < Duration::from_secs(5), "the refresh never landed");
+      thread::yield_now();
+    }
+    assert_eq!(cash.get(&0).map(|a| *a), Ok(2));
+    assert_eq!(loads.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn failed_reload_keeps_stale() {
+    let clock = MockClock::new();
+    let jobs = Arc::new(Mutex::new(Vec::new()));
+    let cash = {
+      let jobs = jobs.clone();
+      RefreshingCache::with_spawner(
+        LruCache::builder(4).clock(clock.clone()).build(),
+        Duration::from_secs(10),
+        |&k: &u8, stale: Option<&Arc<u32>>| match stale {
+          None => Ok(u32::from(k)),
+          Some(_) => Err("unavailable"),
+        },
+        move |job| jobs.lock().unwrap().push(job))
+    };
+    assert_eq!(cash.get(&1).map(|a| *a), Ok(1));
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(cash.get(&1).map(|a| *a), Ok(1));
+    // Already being refreshed.
+    cash.get(&1).unwrap();
+    assert_eq!(jobs.lock().unwrap().len(), 1);
+
+    let job = jobs.lock().unwrap().pop().unwrap();
+    job();
+    assert_eq!(cash.peek(&1).map(|a| *a), Some(1));
+    cash.get(&1).unwrap();
+    assert_eq!(jobs.lock().unwrap().len(), 1);
+  }
+}
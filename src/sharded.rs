@@ -0,0 +1,454 @@
+use std::sync::Arc;
+use std::hash::{ BuildHasher, Hash };
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::convert::Infallible;
+use std::time::{ Duration, Instant };
+use std::vec;
+
+use { load_many, Builder, CacheStats, Entry, Lookup, LruCache, TooHeavy };
+#[cfg(feature = "async")]
+use GetWith;
+
+const DEFAULT_SHARDS: usize = 16;
+
+pub struct ShardedLruCache<K, V: Send, S = RandomState> {
+  hasher: S,
+  shards: Vec<LruCache<K, V>>,
+}
+
+fn shard_limit(limit: usize, shards: usize, shard: usize) -> usize {
+  limit / shards + if shard < limit % shards { 1 } else { 0 }
+}
+
+impl<K: Clone + Hash + Eq, V: Send> ShardedLruCache<K, V> {
+  pub fn with_limit(limit: usize) -> ShardedLruCache<K, V> {
+    ShardedLruCache::with_shards(limit, DEFAULT_SHARDS)
+  }
+
+  pub fn with_shards(limit: usize, shards: usize) -> ShardedLruCache<K, V> {
+    ShardedLruCache::with_shards_and_hasher(limit, shards, RandomState::new())
+  }
+}
+
+impl<K: Clone + Hash + Eq, V: Send, S: BuildHasher> ShardedLruCache<K, V, S> {
+  // The limit is split as evenly as possible, with the first `limit % shards`
+  // shards taking one extra entry. There are never more shards than entries.
+  pub fn with_shards_and_hasher(limit: usize, shards: usize, hasher: S) -> ShardedLruCache<K, V, S> {
+    ShardedLruCache::with_builder(limit, shards, hasher, Builder::new)
+  }
+
+  // Like `with_shards_and_hasher`, but each shard is built from the builder
+  // `build` returns for that shard's limit, so shards can have a listener, a
+  // weigher, another eviction policy or any other setting. A `max_weight`
+  // applies to each shard separately.
+  pub fn with_builder<F>(limit: usize, shards: usize, hasher: S, mut build: F) -> ShardedLruCache<K, V, S>
+      where F: FnMut(usize) -> Builder<K, V> {
+    assert!(limit != 0);
+    assert!(shards != 0);
+    let shards = shards.min(limit);
+    ShardedLruCache {
+      hasher,
+      shards: (0..shards)
+        .map(|i| {
+          let limit = shard_limit(limit, shards, i);
+          let shard = build(limit).build();
+          assert_eq!(shard.capacity(), limit, "a shard's builder must use the limit it is given");
+          shard
+        })
+        .collect(),
+    }
+  }
+
+  pub fn shard_count(&self) -> usize {
+    self.shards.len()
+  }
+
+  fn shard<Q>(&self, k: &Q) -> &LruCache<K, V>
+      where Q: ?Sized + Hash {
+    &self.shards[self.shard_index(k)]
+  }
+
+  fn shard_index<Q>(&self, k: &Q) -> usize
+      where Q: ?Sized + Hash {
+    (self.hasher.hash_one(k) % self.shards.len() as u64) as usize
+  }
+
+  // The indexes of `keys` that belong to each shard, in order.
+  fn group<Q>(&self, keys: &[Q]) -> Vec<Vec<usize>>
+      where Q: Hash {
+    let mut groups = vec![Vec::new(); self.shards.len()];
+    for (i, k) in keys.iter().enumerate() {
+      groups[self.shard_index(k)].push(i);
+    }
+    groups
+  }
+
+  pub fn get<Q>(&self, k: &Q) -> Option<Arc<V>>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    self.shard(k).get(k)
+  }
+
+  pub fn insert(&self, k: K, v: V) -> Option<Arc<V>> {
+    self.shard(&k).insert(k, v)
+  }
+
+  pub fn insert_with_ttl(&self, k: K, v: V, ttl: Duration) -> Option<Arc<V>> {
+    self.shard(&k).insert_with_ttl(k, v, ttl)
+  }
+
+  pub fn try_insert(&self, k: K, v: V) -> Result<Option<Arc<V>>, TooHeavy<K, V>> {
+    self.shard(&k).try_insert(k, v)
+  }
+
+  // Each shard takes its keys under one lock, so the batch as a whole is not
+  // atomic.
+  pub fn get_many<Q>(&self, keys: &[Q]) -> Vec<Option<Arc<V>>>
+      where K: Borrow<Q>, Q: Hash + Eq {
+    let mut found = vec![None; keys.len()];
+    for (shard, group) in self.shards.iter().zip(self.group(keys)) {
+      if group.is_empty() {
+        continue;
+      }
+      let arcs = shard.get_each(group.iter().map(|&i| &keys[i]));
+      for (i, arc) in group.into_iter().zip(arcs) {
+        found[i] = arc;
+      }
+    }
+    found
+  }
+
+  // Entries are inserted in order within each shard, so with more entries
+  // for a shard than its limit the first ones are evicted, see
+  // `LruCache::insert_many`.
+  pub fn insert_many<I>(&self, entries: I)
+      where I: IntoIterator<Item = (K, V)> {
+    let mut groups: Vec<Vec<(K, V)>> = self.shards.iter().map(|_| Vec::new()).collect();
+    for (k, v) in entries {
+      groups[self.shard_index(&k)].push((k, v));
+    }
+    for (shard, group) in self.shards.iter().zip(groups) {
+      if !group.is_empty() {
+        shard.insert_many(group);
+      }
+    }
+  }
+
+  pub fn remove_many<Q>(&self, keys: &[Q]) -> Vec<Option<Arc<V>>>
+      where K: Borrow<Q>, Q: Hash + Eq {
+    let mut removed = vec![None; keys.len()];
+    for (shard, group) in self.shards.iter().zip(self.group(keys)) {
+      if group.is_empty() {
+        continue;
+      }
+      let arcs = shard.remove_each(group.iter().map(|&i| &keys[i]));
+      for (i, arc) in group.into_iter().zip(arcs) {
+        removed[i] = arc;
+      }
+    }
+    removed
+  }
+
+  // The keys that miss in any shard are loaded by a single call to `f`.
+  pub fn get_or_insert_many_with<F>(&self, keys: &[K], mut f: F) -> Vec<Arc<V>>
+      where F: FnMut(&[K]) -> Vec<V> {
+    let loaded = load_many(
+      &self.shards,
+      keys,
+      |k| self.shard_index(k),
+      |missing| Ok::<_, Infallible>(f(missing).into_iter().map(Some).collect()));
+    match loaded {
+      Ok(found) => found.into_iter().map(|arc| arc.expect("every key was found or loaded")).collect(),
+      Err(never) => match never {},
+    }
+  }
+
+  pub fn get_or_insert_with<F>(&self, k: K, f: F) -> Arc<V>
+      where F: FnOnce() -> V {
+    self.shard(&k).get_or_insert_with(k, f)
+  }
+
+  pub fn try_get_or_insert_with<E, F>(&self, k: K, f: F) -> Result<Arc<V>, E>
+      where F: FnOnce() -> Result<V, E> {
+    self.shard(&k).try_get_or_insert_with(k, f)
+  }
+
+  pub fn try_get_or_insert_with_shared<E, F>(&self, k: K, f: F) -> Result<Arc<V>, Arc<E>>
+      where F: FnOnce() -> Result<V, E>, E: Send + Sync + 'static {
+    self.shard(&k).try_get_or_insert_with_shared(k, f)
+  }
+
+  // Each shard has its own negative cache, set with `Builder::negative_cache`
+  // in `with_builder`.
+  pub fn get_or_insert_with_optional<F>(&self, k: K, f: F) -> Option<Arc<V>>
+      where F: FnOnce() -> Option<V> {
+    self.shard(&k).get_or_insert_with_optional(k, f)
+  }
+
+  pub fn lookup<Q>(&self, k: &Q) -> Lookup<V>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    self.shard(k).lookup(k)
+  }
+
+  pub fn entry(&self, k: K) -> Entry<'_, K, V> {
+    self.shard(&k).entry(k)
+  }
+
+  pub fn compute<F>(&self, k: K, f: F) -> Option<Arc<V>>
+      where F: FnOnce(Option<&Arc<V>>) -> Option<V> {
+    self.shard(&k).compute(k, f)
+  }
+
+  pub fn replace_if(&self, k: K, expected: &Arc<V>, v: V) -> bool {
+    self.shard(&k).replace_if(k, expected, v)
+  }
+
+  #[cfg(feature = "async")]
+  pub fn get_with<F, Fut>(&self, k: K, f: F) -> GetWith<'_, K, V, F, Fut>
+      where F: FnOnce() -> Fut, Fut: ::std::future::Future<Output = V> {
+    self.shard(&k).get_with(k, f)
+  }
+
+  pub fn remove<Q>(&self, k: &Q) -> Option<Arc<V>>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    self.shard(k).remove(k)
+  }
+
+  pub fn contains_key<Q>(&self, k: &Q) -> bool
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    self.shard(k).contains_key(k)
+  }
+
+  pub fn peek<Q>(&self, k: &Q) -> Option<Arc<V>>
+      where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+    self.shard(k).peek(k)
+  }
+
+  // The shards' snapshots merged by when each entry was last used, most
+  // recent first. Each shard is locked in turn, so like `len` this is not
+  // consistent across shards while other threads are writing.
+  pub fn snapshot(&self) -> Vec<(K, Arc<V>)> {
+    let mut shards: Vec<_> = self.shards.iter()
+      .map(|shard| shard.snapshot_accessed().into_iter().peekable())
+      .collect();
+    let mut merged = Vec::with_capacity(shards.iter().map(|shard| shard.len()).sum());
+    loop {
+      // Taking the newest head keeps each shard's own order.
+      let newest = shards.iter_mut()
+        .enumerate()
+        .filter_map(|(i, shard)| shard.peek().map(|&(_, _, accessed)| (accessed, i)))
+        .fold(None, |newest: Option<(Instant, usize)>, (accessed, i)| match newest {
+          Some((best, _)) if best >= accessed => newest,
+          _ => Some((accessed, i)),
+        });
+      match newest {
+        Some((_, i)) => {
+          let (k, v, _) = shards[i].next().unwrap();
+          merged.push((k, v));
+        },
+        None => return merged,
+      }
+    }
+  }
+
+  pub fn iter(&self) -> vec::IntoIter<(K, Arc<V>)> {
+    self.snapshot().into_iter()
+  }
+
+  pub fn keys(&self) -> Vec<K> {
+    self.snapshot().into_iter().map(|(k, _)| k).collect()
+  }
+
+  // Each shard is locked in turn while `f` runs on its entries.
+  pub fn retain<F>(&self, mut f: F)
+      where F: FnMut(&K, &Arc<V>) -> bool {
+    for shard in &self.shards {
+      shard.retain(&mut f);
+    }
+  }
+
+  // Each shard is locked in turn, so this is not a consistent snapshot while
+  // other threads are writing.
+  pub fn len(&self) -> usize {
+    self.shards.iter().map(LruCache::len).sum()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.shards.iter().all(LruCache::is_empty)
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.shards.iter().map(LruCache::capacity).sum()
+  }
+
+  // Split between the shards like the limit given at construction, but the
+  // shard count does not change. Every shard keeps room for at least one
+  // entry, so a limit below the shard count is raised to it.
+  pub fn set_limit(&self, limit: usize) {
+    assert!(limit != 0);
+    let shards = self.shards.len();
+    let limit = limit.max(shards);
+    for (i, shard) in self.shards.iter().enumerate() {
+      shard.set_limit(shard_limit(limit, shards, i));
+    }
+  }
+
+  // The counters of every shard added together, all zero unless the shards
+  // were built with `record_stats`.
+  pub fn stats(&self) -> CacheStats {
+    self.shards.iter().map(LruCache::stats).fold(CacheStats::default(), |total, stats| CacheStats {
+      hits: total.hits + stats.hits,
+      misses: total.misses + stats.misses,
+      inserts: total.inserts + stats.inserts,
+      replacements: total.replacements + stats.replacements,
+      evictions: total.evictions + stats.evictions,
+      expirations: total.expirations + stats.expirations,
+    })
+  }
+
+  pub fn reset_stats(&self) {
+    for shard in &self.shards {
+      shard.reset_stats();
+    }
+  }
+
+  pub fn clear(&self) {
+    for shard in &self.shards {
+      shard.clear();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn splits_limit() {
+    let cash = ShardedLruCache::<u8, u8>::with_shards(10, 3);
+    let limits: Vec<_> = cash.shards.iter().map(LruCache::capacity).collect();
+    assert_eq!(limits, vec![4, 3, 3]);
+
+    let cash = ShardedLruCache::<u8, u8>::with_shards(2, 16);
+    assert_eq!(cash.shard_count(), 2);
+  }
+
+  #[test]
+  fn smoke() {
+    let cash = ShardedLruCache::with_shards(64, 4);
+    for i in 0..64u32 {
+      cash.insert(i, i);
+    }
+    assert_eq!(cash.get(&63).map(|a| *a), Some(63));
+    assert_eq!(cash.insert(1000, 1000), None);
+    assert_eq!(cash.insert(1000, 1001).map(|a| *a), Some(1000));
+    assert_eq!(cash.get(&1000).map(|a| *a), Some(1001));
+    assert!(cash.len() <= cash.capacity());
+    assert_eq!(cash.remove(&1000).map(|a| *a), Some(1001));
+    assert!(!cash.contains_key(&1000));
+    cash.clear();
+    assert!(cash.is_empty());
+  }
+
+  #[test]
+  fn with_builder() {
+    use std::sync::Mutex;
+    use RemovalCause;
+
+    let removed = Arc::new(Mutex::new(Vec::new()));
+    let cash = ShardedLruCache::with_builder(4, 2, RandomState::new(), |limit| {
+      let removed = removed.clone();
+      LruCache::builder(limit).removal_listener(move |k, _, cause| removed.lock().unwrap().push((k, cause)))
+    });
+    for i in 0..8u32 {
+      cash.insert(i, i);
+    }
+    // Keys may not split evenly, but every shard holds at most two.
+    let evicted = removed.lock().unwrap().iter().filter(|&&(_, cause)| cause == RemovalCause::Capacity).count();
+    assert_eq!(cash.len() + evicted, 8);
+    assert!(cash.len() <= 4);
+  }
+
+  #[test]
+  fn stats() {
+    let cash = ShardedLruCache::with_builder(4, 2, RandomState::new(), |limit| LruCache::builder(limit).record_stats());
+    for i in 0..8u32 {
+      cash.insert(i, i);
+    }
+    cash.get(&100);
+    let stats = cash.stats();
+    assert_eq!((stats.inserts, stats.misses), (8, 1));
+    assert_eq!(stats.evictions as usize, 8 - cash.len());
+    cash.reset_stats();
+    assert_eq!(cash.stats(), CacheStats::default());
+  }
+
+  #[test]
+  fn set_limit_below_shard_count() {
+    let cash = ShardedLruCache::with_shards(64, 16);
+    for i in 0..64u32 {
+      cash.insert(i, i);
+    }
+    cash.set_limit(4);
+    assert_eq!(cash.capacity(), 16);
+    assert!(cash.len() <= 16);
+    cash.set_limit(32);
+    assert_eq!(cash.capacity(), 32);
+  }
+
+  #[test]
+  fn bulk_operations() {
+    let cash = ShardedLruCache::with_shards(64, 4);
+    cash.insert_many(vec![(10u32, 10u32), (11, 11), (10, 12)]);
+    let found: Vec<_> = cash.get_many(&[10, 1, 11]).into_iter().map(|arc| arc.map(|a| *a)).collect();
+    assert_eq!(found, vec![Some(12), None, Some(11)]);
+    let removed: Vec<_> = cash.remove_many(&[11, 1]).into_iter().map(|arc| arc.map(|a| *a)).collect();
+    assert_eq!(removed, vec![Some(11), None]);
+
+    let mut calls = Vec::new();
+    let values = cash.get_or_insert_many_with(&[10, 20, 21, 22, 20], |missing| {
+      calls.push(missing.to_vec());
+      missing.iter().map(|k| k * 10).collect()
+    });
+    assert_eq!(values.iter().map(|a| **a).collect::<Vec<_>>(), vec![12, 200, 210, 220, 200]);
+    assert_eq!(calls.len(), 1);
+    calls[0].sort();
+    assert_eq!(calls[0], vec![20, 21, 22]);
+  }
+
+  #[test]
+  fn snapshot_and_retain() {
+    use std::time::Duration;
+    use MockClock;
+
+    let clock = MockClock::new();
+    let cash = ShardedLruCache::with_builder(64, 4, RandomState::new(), |limit| {
+      LruCache::builder(limit).clock(clock.clone())
+    });
+    for i in 0..8u32 {
+      clock.advance(Duration::from_millis(1));
+      cash.insert(i, i);
+    }
+    clock.advance(Duration::from_millis(1));
+    cash.get(&2);
+    assert_eq!(cash.keys(), vec![2, 7, 6, 5, 4, 3, 1, 0]);
+
+    cash.retain(|&k, _| k % 2 == 0);
+    assert_eq!(cash.iter().map(|(k, _)| k).collect::<Vec<_>>(), vec![2, 6, 4, 0]);
+  }
+
+  #[test]
+  fn negative_cache() {
+    use std::time::Duration;
+
+    let cash = ShardedLruCache::with_builder(8, 2, RandomState::new(), |limit| {
+      LruCache::builder(limit).negative_cache(limit, Duration::from_secs(60))
+    });
+    assert!(matches!(cash.lookup(&1u32), Lookup::NotCached));
+    assert_eq!(cash.get_or_insert_with_optional(1u32, || None::<u32>), None);
+    assert!(matches!(cash.lookup(&1), Lookup::Absent));
+    assert_eq!(cash.get_or_insert_with_optional(1, || Some(1)), None);
+    cash.insert(1, 1);
+    assert!(matches!(cash.lookup(&1), Lookup::Present(_)));
+  }
+}
@@ -0,0 +1,112 @@
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use RemovalCause;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+  pub hits: u64,
+  pub misses: u64,
+  // New entries stored, including values stored by the loader methods.
+  pub inserts: u64,
+  pub replacements: u64,
+  pub evictions: u64,
+  pub expirations: u64,
+}
+
+impl CacheStats {
+  pub fn requests(&self) -> u64 {
+    self.hits + self.misses
+  }
+
+  // 1.0 when there have been no requests.
+  pub fn hit_ratio(&self) -> f64 {
+    match self.requests() {
+      0 => 1.0,
+      requests => self.hits as f64 / requests as f64,
+    }
+  }
+
+  pub fn miss_ratio(&self) -> f64 {
+    1.0 - self.hit_ratio()
+  }
+}
+
+// Relaxed counters, each is exact but a snapshot taken while other threads
+// are using the cache may not be consistent across counters.
+pub struct StatsCounter {
+  enabled: bool,
+  hits: AtomicU64,
+  misses: AtomicU64,
+  inserts: AtomicU64,
+  replacements: AtomicU64,
+  evictions: AtomicU64,
+  expirations: AtomicU64,
+}
+
+impl StatsCounter {
+  pub fn new(enabled: bool) -> StatsCounter {
+    StatsCounter {
+      enabled,
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+      inserts: AtomicU64::new(0),
+      replacements: AtomicU64::new(0),
+      evictions: AtomicU64::new(0),
+      expirations: AtomicU64::new(0),
+    }
+  }
+
+  fn add(&self, counter: &AtomicU64) {
+    if self.enabled {
+      counter.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  pub fn lookup<T>(&self, found: &Option<T>) {
+    self.add(if found.is_some() { &self.hits } else { &self.misses });
+  }
+
+  pub fn insert(&self) {
+    self.add(&self.inserts);
+  }
+
+  pub fn removal(&self, cause: RemovalCause) {
+    match cause {
+      RemovalCause::Replaced => self.add(&self.replacements),
+      RemovalCause::Capacity => self.add(&self.evictions),
+      RemovalCause::Expired => self.add(&self.expirations),
+      RemovalCause::Explicit | RemovalCause::Cleared => (),
+    }
+  }
+
+  pub fn snapshot(&self) -> CacheStats {
+    CacheStats {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      inserts: self.inserts.load(Ordering::Relaxed),
+      replacements: self.replacements.load(Ordering::Relaxed),
+      evictions: self.evictions.load(Ordering::Relaxed),
+      expirations: self.expirations.load(Ordering::Relaxed),
+    }
+  }
+
+  pub fn reset(&self) {
+    for counter in &[&self.hits, &self.misses, &self.inserts, &self.replacements, &self.evictions, &self.expirations] {
+      counter.store(0, Ordering::Relaxed);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ratios() {
+    let stats = CacheStats { hits: 3, misses: 1, ..CacheStats::default() };
+    assert_eq!(stats.requests(), 4);
+    assert_eq!(stats.hit_ratio(), 0.75);
+    assert_eq!(stats.miss_ratio(), 0.25);
+    assert_eq!(CacheStats::default().hit_ratio(), 1.0);
+  }
+}
@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::hash::Hash;
+use std::ops::Deref;
+use std::sync::{ Arc, Mutex, PoisonError };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::Duration;
+
+use { nanos, LruCache, RemovalCause };
+use flight::SharedError;
+
+// The system of record behind a `StoreBackedCache`, such as a database.
+pub trait CacheStore<K, V>: Send + Sync {
+  type Error;
+
+  fn load(&self, k: &K) -> Result<Option<V>, Self::Error>;
+
+  // The values for `keys`, in the same order.
+  fn load_all(&self, keys: &[K]) -> Result<Vec<Option<V>>, Self::Error> {
+    keys.iter().map(|k| self.load(k)).collect()
+  }
+
+  fn write(&self, k: &K, v: &V) -> Result<(), Self::Error>;
+
+  fn delete(&self, k: &K) -> Result<(), Self::Error>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteMode {
+  // Every `insert` and `remove` goes to the store before the cache.
+  Through,
+  // Writes only go to the cache and are queued, to be written to the store
+  // when the entry is evicted or expires, on `flush`, or when the cache is
+  // dropped. There is no background thread: the first `get`, `insert` or
+  // `remove` after `flush_interval` since the last flush runs the flush
+  // itself, on the caller's thread, before doing its own work.
+  Behind { flush_interval: Duration },
+}
+
+// Queued writes, `None` for a delete. A change stays queued until the store
+// has applied it, so reads never fall through to a row it replaces.
+type Dirty<K, V> = Mutex<HashMap<K, Option<Arc<V>>>>;
+
+fn same_change<V>(a: &Option<Arc<V>>, b: &Option<Arc<V>>) -> bool {
+  match (a, b) {
+    (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+    (None, None) => true,
+    _ => false,
+  }
+}
+
+// An `LruCache` in front of a `CacheStore`. Misses are read through from the
+// store, with concurrent misses sharing one load, and writes go to the store
+// as set by the `WriteMode`. The rest of the `LruCache` API is available
+// through `Deref`, but bypasses the store.
+pub struct StoreBackedCache<K: Clone + Hash + Eq, V: Send, S: CacheStore<K, V>> {
+  cache: LruCache<K, V>,
+  store: Arc<S>,
+  mode: WriteMode,
+  dirty: Arc<Dirty<K, V>>,
+  last_flush: AtomicU64,
+}
+
+// Why a read through did not produce a value, shared with waiting threads.
+enum Miss<E> {
+  Missing,
+  Failed(Arc<E>),
+}
+
+impl<E> Clone for Miss<E> {
+  fn clone(&self) -> Miss<E> {
+    match *self {
+      Miss::Missing => Miss::Missing,
+      Miss::Failed(ref error) => Miss::Failed(error.clone()),
+    }
+  }
+}
+
+impl<K, V, S> StoreBackedCache<K, V, S>
+    where K: Clone + Hash + Eq + Send + Sync + 'static,
+          V: Send + Sync + 'static,
+          S: CacheStore<K, V> + 'static,
+          S::Error: Send + Sync + 'static {
+  // Any removal listener on `cache` still runs, after an evicted entry has
+  // been written back.
+  pub fn new(mut cache: LruCache<K, V>, store: S, mode: WriteMode) -> StoreBackedCache<K, V, S> {
+    let store = Arc::new(store);
+    let dirty = Arc::new(Mutex::new(HashMap::new()));
+    if let WriteMode::Behind { .. } = mode {
+      let listener = cache.listener.take();
+      let (store, dirty) = (store.clone(), dirty.clone());
+      cache.listener = Some(Box::new(move |k, arc, cause| {
+        if cause == RemovalCause::Capacity || cause == RemovalCause::Expired {
+          let _ = write_back(&*store, &dirty, &k, &arc);
+        }
+        if let Some(ref listener) = listener {
+          listener(k, arc, cause);
+        }
+      }));
+      cache.inner.get_mut().unwrap().notify = true;
+    }
+    StoreBackedCache { last_flush: AtomicU64::new(cache.now()), cache, store, mode, dirty }
+  }
+
+  pub fn store(&self) -> &S {
+    &self.store
+  }
+
+  // Loads a miss from the store. Queued writes are seen before the store.
+  pub fn get(&self, k: &K) -> Result<Option<Arc<V>>, Arc<S::Error>> {
+    self.maybe_flush();
+    if let Some(arc) = self.cache.get(k) {
+      return Ok(Some(arc));
+    }
+    if let Some(queued) = self.dirty.lock().unwrap().get(k) {
+      return Ok(queued.clone());
+    }
+    let load = || match self.store.load(k) {
+      Ok(Some(v)) => Ok(v),
+      Ok(None) => Err(Miss::Missing),
+      Err(error) => Err(Miss::Failed(Arc::new(error))),
+    };
+    let loaded = self.cache.load(
+      k.clone(),
+      load,
+      |miss| Some(Arc::new(miss.clone()) as SharedError),
+      |error| error.downcast::<Miss<S::Error>>().ok().map(|miss| (*miss).clone()));
+    match loaded {
+      Ok(arc) => Ok(Some(arc)),
+      Err(Miss::Missing) => Ok(None),
+      Err(Miss::Failed(error)) => Err(error),
+    }
+  }
+
+  // Like `get` for each key, but the misses are read through with a single
+  // `load_all`. Unlike with `get`, if that fails other threads waiting on
+  // the same keys do not get the error, they retry with their own load.
+  pub fn get_many(&self, keys: &[K]) -> Result<Vec<Option<Arc<V>>>, Arc<S::Error>> {
+    self.maybe_flush();
+    let mut found = vec![None; keys.len()];
+    let mut rest = Vec::new();
+    {
+      let dirty = self.dirty.lock().unwrap();
+      for (i, k) in keys.iter().enumerate() {
+        match dirty.get(k) {
+          Some(queued) => found[i] = queued.clone(),
+          None => rest.push(i),
+        }
+      }
+    }
+    let rest_keys: Vec<K> = rest.iter().map(|&i| keys[i].clone()).collect();
+    let loaded = self.cache.load_many(&rest_keys, |missing| self.store.load_all(missing).map_err(Arc::new))?;
+    for (i, arc) in rest.into_iter().zip(loaded) {
+      found[i] = arc;
+    }
+    Ok(found)
+  }
+
+  // Returns the replaced cached value. With `WriteMode::Through` nothing is
+  // cached if the store fails.
+  pub fn insert(&self, k: K, v: V) -> Result<Option<Arc<V>>, S::Error> {
+    self.maybe_flush();
+    let arc = Arc::new(v);
+    match self.mode {
+      WriteMode::Through => self.store.write(&k, &arc)?,
+      WriteMode::Behind { .. } => {
+        self.dirty.lock().unwrap().insert(k.clone(), Some(arc.clone()));
+      },
+    }
+    Ok(self.cache.insert_arc(k, arc))
+  }
+
+  // Returns the removed cached value. With `WriteMode::Through` nothing is
+  // removed if the store fails.
+  pub fn remove(&self, k: &K) -> Result<Option<Arc<V>>, S::Error> {
+    self.maybe_flush();
+    match self.mode {
+      WriteMode::Through => self.store.delete(k)?,
+      WriteMode::Behind { .. } => {
+        self.dirty.lock().unwrap().insert(k.clone(), None);
+      },
+    }
+    Ok(self.cache.remove(k))
+  }
+
+  // Writes every queued change to the store. Stops at the first error,
+  // leaving it and the rest queued.
+  pub fn flush(&self) -> Result<(), S::Error> {
+    self.last_flush.store(self.cache.now(), Ordering::Relaxed);
+    flush(&*self.store, &self.dirty)
+  }
+
+  fn maybe_flush(&self) {
+    if let WriteMode::Behind { flush_interval } = self.mode {
+      let now = self.cache.now();
+      let last = self.last_flush.load(Ordering::Relaxed);
+      if now.saturating_sub(last) >= nanos(flush_interval)
+          && self.last_flush.compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+        // Failed writes stay queued for the next flush.
+        let _ = self.flush();
+      }
+    }
+  }
+}
+
+fn flush<K: Clone + Hash + Eq, V, S: CacheStore<K, V>>(store: &S, dirty: &Dirty<K, V>) -> Result<(), S::Error> {
+  // Also used while dropping the cache, possibly during a panic.
+  let lock = || dirty.lock().unwrap_or_else(PoisonError::into_inner);
+  let queued: Vec<_> = lock().iter().map(|(k, change)| (k.clone(), change.clone())).collect();
+  for (k, change) in queued {
+    apply(store, dirty, &k, &change)?;
+  }
+  Ok(())
+}
+
+// Writes an evicted entry if it is still the queued change for its key.
+fn write_back<K, V, S>(store: &S, dirty: &Dirty<K, V>, k: &K, arc: &Arc<V>) -> Result<(), S::Error>
+    where K: Clone + Hash + Eq, S: CacheStore<K, V> {
+  let change = Some(arc.clone());
+  if !dirty.lock().unwrap().get(k).is_some_and(|queued| same_change(queued, &change)) {
+    return Ok(());
+  }
+  apply(store, dirty, k, &change)
+}
+
+// Sends one queued change to the store, then unqueues it unless a newer one
+// was queued meanwhile. On failure it stays queued.
+fn apply<K, V, S>(store: &S, dirty: &Dirty<K, V>, k: &K, change: &Option<Arc<V>>) -> Result<(), S::Error>
+    where K: Hash + Eq, S: CacheStore<K, V> {
+  match *change {
+    Some(ref arc) => store.write(k, arc)?,
+    None => store.delete(k)?,
+  }
+  let mut dirty = dirty.lock().unwrap_or_else(PoisonError::into_inner);
+  if dirty.get(k).is_some_and(|queued| same_change(queued, change)) {
+    dirty.remove(k);
+  }
+  Ok(())
+}
+
+// A best effort: writes that fail are lost, call `flush` first to see errors.
+impl<K: Clone + Hash + Eq, V: Send, S: CacheStore<K, V>> Drop for StoreBackedCache<K, V, S> {
+  fn drop(&mut self) {
+    let _ = flush(&*self.store, &self.dirty);
+  }
+}
+
+impl<K: Clone + Hash + Eq, V: Send, S: CacheStore<K, V>> Deref for StoreBackedCache<K, V, S> {
+  type Target = LruCache<K, V>;
+
+  fn deref(&self) -> &LruCache<K, V> {
+    &self.cache
+  }
+}
+
+// A `CacheStore` in a `HashMap`, for tests.
+pub struct MemoryStore<K, V> {
+  map: Mutex<HashMap<K, V>>,
+}
+
+impl<K: Clone + Hash + Eq, V: Clone> MemoryStore<K, V> {
+  pub fn new() -> MemoryStore<K, V> {
+    MemoryStore { map: Mutex::new(HashMap::new()) }
+  }
+
+  pub fn get(&self, k: &K) -> Option<V> {
+    self.map.lock().unwrap().get(k).cloned()
+  }
+
+  pub fn insert(&self, k: K, v: V) {
+    self.map.lock().unwrap().insert(k, v);
+  }
+
+  pub fn len(&self) -> usize {
+    self.map.lock().unwrap().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl<K: Clone + Hash + Eq, V: Clone> Default for MemoryStore<K, V> {
+  fn default() -> MemoryStore<K, V> {
+    MemoryStore::new()
+  }
+}
+
+impl<K, V> CacheStore<K, V> for MemoryStore<K, V>
+    where K: Clone + Hash + Eq + Send, V: Clone + Send {
+  type Error = Infallible;
+
+  fn load(&self, k: &K) -> Result<Option<V>, Infallible> {
+    Ok(self.get(k))
+  }
+
+  fn write(&self, k: &K, v: &V) -> Result<(), Infallible> {
+    self.insert(k.clone(), v.clone());
+    Ok(())
+  }
+
+  fn delete(&self, k: &K) -> Result<(), Infallible> {
+    self.map.lock().unwrap().remove(k);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::mpsc;
+  use std::thread;
+
+  use MockClock;
+  use super::*;
+
+  #[test]
+  fn read_and_write_through() {
+    let store = MemoryStore::new();
+    store.insert(0u8, 0u32);
+    let cash = StoreBackedCache::new(LruCache::with_limit(2), store, WriteMode::Through);
+    assert_eq!(cash.get(&0), Ok(Some(Arc::new(0))));
+    assert!(cash.contains_key(&0));
+    assert_eq!(cash.get(&1), Ok(None));
+    assert!(!cash.contains_key(&1));
+
+    cash.insert(1, 10).unwrap();
+    assert_eq!(cash.store().get(&1), Some(10));
+    cash.store().insert(2, 20);
+    let found: Vec<_> = cash.get_many(&[2, 3, 1]).unwrap().into_iter().map(|arc| arc.map(|a| *a)).collect();
+    assert_eq!(found, vec![Some(20), None, Some(10)]);
+    assert!(cash.contains_key(&2));
+    cash.remove(&0).unwrap();
+    assert_eq!(cash.store().get(&0), None);
+  }
+
+  // Blocks in `load` or `delete` until released, after reading the row or
+  // before deleting it.
+  struct GatedStore {
+    store: MemoryStore<u8, u32>,
+    gate_deletes: bool,
+    reached: Mutex<mpsc::Sender<()>>,
+    release: Mutex<mpsc::Receiver<()>>,
+  }
+
+  impl GatedStore {
+    fn new(gate_deletes: bool) -> (GatedStore, mpsc::Receiver<()>, mpsc::Sender<()>) {
+      let (reached, wait_reached) = mpsc::channel();
+      let (release, wait_release) = mpsc::channel();
+      let store = GatedStore {
+        store: MemoryStore::new(),
+        gate_deletes,
+        reached: Mutex::new(reached),
+        release: Mutex::new(wait_release),
+      };
+      (store, wait_reached, release)
+    }
+
+    fn gate(&self) {
+      self.reached.lock().unwrap().send(()).unwrap();
+      self.release.lock().unwrap().recv().unwrap();
+    }
+  }
+
+  impl CacheStore<u8, u32> for GatedStore {
+    type Error = Infallible;
+
+    fn load(&self, k: &u8) -> Result<Option<u32>, Infallible> {
+      let v = self.store.load(k);
+      if !self.gate_deletes {
+        self.gate();
+      }
+      v
+    }
+
+    fn write(&self, k: &u8, v: &u32) -> Result<(), Infallible> {
+      self.store.write(k, v)
+    }
+
+    fn delete(&self, k: &u8) -> Result<(), Infallible> {
+      if self.gate_deletes {
+        self.gate();
+      }
+      self.store.delete(k)
+    }
+  }
+
+  #[test]
+  fn write_through_supersedes_read_through() {
+    let (store, wait_loaded, release) = GatedStore::new(false);
+    store.store.insert(0, 1);
+    let cash = Arc::new(StoreBackedCache::new(LruCache::with_limit(2), store, WriteMode::Through));
+    let reader = {
+      let cash = cash.clone();
+      thread::spawn(move || cash.get(&0).unwrap().map(|a| *a))
+    };
+    wait_loaded.recv().unwrap();
+    cash.insert(0, 2).unwrap();
+    release.send(()).unwrap();
+    assert_eq!(reader.join().unwrap(), Some(1));
+    assert_eq!(cash.peek(&0).map(|a| *a), Some(2));
+    assert_eq!(cash.store().store.get(&0), Some(2));
+  }
+
+  #[test]
+  fn queued_delete_visible_while_flushing() {
+    let (store, wait_deleting, release) = GatedStore::new(true);
+    store.store.insert(0, 1);
+    let cash = Arc::new(StoreBackedCache::new(
+      LruCache::with_limit(2),
+      store,
+      WriteMode::Behind { flush_interval: Duration::from_secs(3600) }));
+    cash.remove(&0).unwrap();
+    let flusher = {
+      let cash = cash.clone();
+      thread::spawn(move || cash.flush().unwrap())
+    };
+    wait_deleting.recv().unwrap();
+    // The store still has the old row, but the delete is still queued.
+    assert_eq!(cash.get(&0), Ok(None));
+    assert!(!cash.contains_key(&0));
+    release.send(()).unwrap();
+    flusher.join().unwrap();
+    assert_eq!(cash.store().store.get(&0), None);
+    assert_eq!(cash.get(&0), Ok(None));
+  }
+
+  #[test]
+  fn write_behind() {
+    let clock = MockClock::new();
+    let cash = StoreBackedCache::new(
+      LruCache::builder(2).clock(clock.clone()).build(),
+      MemoryStore::new(),
+      WriteMode::Behind { flush_interval: Duration::from_secs(10) });
+    cash.insert(0u8, 0u32).unwrap();
+    cash.insert(1, 1).unwrap();
+    assert!(cash.store().is_empty());
+
+    // Evicts and writes back 0.
+    cash.insert(2, 2).unwrap();
+    assert_eq!(cash.store().get(&0), Some(0));
+    assert_eq!(cash.store().len(), 1);
+
+    cash.remove(&0).unwrap();
+    assert_eq!(cash.get(&0), Ok(None));
+    assert_eq!(cash.store().get(&0), Some(0));
+
+    clock.advance(Duration::from_secs(10));
+    cash.get(&1).unwrap();
+    assert_eq!(cash.store().get(&0), None);
+    assert_eq!(cash.store().get(&1), Some(1));
+    assert_eq!(cash.store().get(&2), Some(2));
+
+    cash.insert(1, 11).unwrap();
+    cash.flush().unwrap();
+    assert_eq!(cash.store().get(&1), Some(11));
+
+    cash.insert(1, 12).unwrap();
+    let store = cash.store.clone();
+    drop(cash);
+    assert_eq!(store.get(&1), Some(12));
+  }
+}